@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    DEVICES, SETTINGS, TOKENS,
+    mappings::{DEVICE_NAMESPACE, Kind, V1_SERIAL},
+    paths::state_file,
+};
+
+/// File seen device ids, aliases and other things known about devices are persisted to
+pub const KNOWN_FILE: &str = "devices.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Known {
+    /// Ids of all the devices ever connected
+    seen: HashSet<String>,
+    /// Ids of the devices registered under id of another device, by their own id
+    aliases: HashMap<String, String>,
+    /// Ids of the devices boot logo was cleared for
+    #[serde(default)]
+    logo_cleared: HashSet<String>,
+    /// Kinds of the seen devices by their id, to tell apart devices of the same kind
+    #[serde(default)]
+    kinds: HashMap<String, String>,
+}
+
+/// Known devices, loaded on first use
+static KNOWN: LazyLock<Mutex<Option<Known>>> = LazyLock::new(|| Mutex::new(None));
+
+async fn load() -> Known {
+    let data = match tokio::fs::read(state_file(KNOWN_FILE)).await {
+        Ok(data) => data,
+        Err(err) => {
+            log::info!("No known devices loaded: {}", err);
+            return Known::default();
+        }
+    };
+
+    serde_json::from_slice(&data).unwrap_or_else(|err| {
+        log::error!("Unable to parse {}: {}", KNOWN_FILE, err);
+        Known::default()
+    })
+}
+
+async fn persist(known: &Known) {
+    let data = serde_json::to_vec(known).unwrap(); // Sets and maps of strings always serialize
+
+    if let Err(err) = tokio::fs::write(state_file(KNOWN_FILE), data).await {
+        log::error!("Unable to write {}: {}", KNOWN_FILE, err);
+    }
+}
+
+/// Locks known devices, loading them first if needed
+async fn known() -> MutexGuard<'static, Option<Known>> {
+    let mut known = KNOWN.lock().await;
+
+    if known.is_none() {
+        *known = Some(load().await);
+    }
+
+    known
+}
+
+/// Checks if boot logo was already cleared for the device
+pub async fn is_logo_cleared(id: &str) -> bool {
+    known()
+        .await
+        .as_ref()
+        .is_some_and(|known| known.logo_cleared.contains(id))
+}
+
+/// Remembers that boot logo was cleared for the device, so it's not cleared again
+pub async fn set_logo_cleared(id: &str) {
+    let mut known = known().await;
+    let known = known.as_mut().unwrap(); // Loaded by `known`
+
+    if known.logo_cleared.insert(id.to_string()) {
+        persist(known).await;
+    }
+}
+
+async fn is_active(id: &str) -> bool {
+    DEVICES.read().await.contains_key(id) || TOKENS.read().await.contains_key(id)
+}
+
+/// Returns id device should be registered with, which is the id of the "v1" device of a different model
+/// if this device replaced it, and migration is enabled
+pub async fn resolve(id: String) -> String {
+    let mut known = known().await;
+    let known = known.as_mut().unwrap(); // Loaded by `known`
+
+    if let Some(alias) = known.aliases.get(&id) {
+        log::info!("Device {} is registered as {}", id, alias);
+        return alias.clone();
+    }
+
+    if known.seen.contains(&id) {
+        return id;
+    }
+
+    let prefix = format!("{}-{}-", DEVICE_NAMESPACE, V1_SERIAL);
+
+    if id.starts_with(&prefix) && SETTINGS.read().await.migrate_v1_ids {
+        let mut previous = Vec::new();
+
+        for seen in known.seen.iter().filter(|seen| seen.starts_with(&prefix)) {
+            // Never take over id of a device that is connected, or was taken over already
+            if !is_active(seen).await && !known.aliases.values().any(|alias| alias == seen) {
+                previous.push(seen.clone());
+            }
+        }
+
+        match previous.as_slice() {
+            [previous] => {
+                log::warn!(
+                    "Device {} was never seen before, registering it as {} so profiles carry over",
+                    id,
+                    previous
+                );
+
+                known.aliases.insert(id, previous.clone());
+                persist(known).await;
+
+                return previous.clone();
+            }
+            [] => {}
+            _ => log::warn!(
+                "Device {} could replace any of {:?}, not migrating its id",
+                id,
+                previous
+            ),
+        }
+    }
+
+    known.seen.insert(id.clone());
+    persist(known).await;
+
+    id
+}
+
+/// Returns name device is registered in OpenDeck with, numbered if several devices of its kind were seen
+///
+/// Numbers follow the order of device ids, so they stay the same between restarts
+pub async fn display_name(id: &str, kind: &Kind) -> String {
+    let mut known = known().await;
+    let known = known.as_mut().unwrap(); // Loaded by `known`
+
+    if known.remember_kind(id, kind) {
+        persist(known).await;
+    }
+
+    known.name(id, kind)
+}
+
+impl Known {
+    /// Remembers kind of the device, returning true if it wasn't known yet
+    fn remember_kind(&mut self, id: &str, kind: &Kind) -> bool {
+        let kind_name = format!("{:?}", kind);
+
+        if self.kinds.get(id) == Some(&kind_name) {
+            return false;
+        }
+
+        self.kinds.insert(id.to_string(), kind_name);
+
+        true
+    }
+
+    /// Returns name of the device, numbered among the seen devices of the same kind
+    fn name(&self, id: &str, kind: &Kind) -> String {
+        let kind_name = format!("{:?}", kind);
+
+        let mut ids: Vec<&String> = self
+            .kinds
+            .iter()
+            .filter(|(_, kind)| **kind == kind_name)
+            .map(|(id, _)| id)
+            .collect();
+
+        if ids.len() < 2 {
+            return kind.human_name();
+        }
+
+        ids.sort();
+
+        match ids.iter().position(|known| *known == id) {
+            Some(index) => format!("{} #{}", kind.human_name(), index + 1),
+            None => kind.human_name(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connects the devices in the given order, returning their names once all of them are connected
+    fn connect(known: &mut Known, ids: &[&str], kind: &Kind) -> Vec<String> {
+        for id in ids {
+            known.remember_kind(id, kind);
+        }
+
+        ids.iter().map(|id| known.name(id, kind)).collect()
+    }
+
+    #[test]
+    fn single_device_is_not_numbered() {
+        let mut known = Known::default();
+
+        assert_eq!(connect(&mut known, &["b"], &Kind::AKP153), ["Ajazz AKP153"]);
+    }
+
+    #[test]
+    fn two_devices_of_same_kind_are_numbered_by_id() {
+        let mut known = Known::default();
+
+        assert_eq!(
+            connect(&mut known, &["b", "a"], &Kind::AKP153),
+            ["Ajazz AKP153 #2", "Ajazz AKP153 #1"]
+        );
+    }
+
+    #[test]
+    fn three_devices_of_same_kind_are_numbered_by_id() {
+        let mut known = Known::default();
+
+        assert_eq!(
+            connect(&mut known, &["c", "a", "b"], &Kind::HSV293SV3),
+            [
+                "Mirabox HSV293SV3 #3",
+                "Mirabox HSV293SV3 #1",
+                "Mirabox HSV293SV3 #2"
+            ]
+        );
+    }
+
+    #[test]
+    fn devices_of_other_kinds_are_not_counted() {
+        let mut known = Known::default();
+        known.remember_kind("a", &Kind::HSV293SV3);
+
+        assert_eq!(connect(&mut known, &["b"], &Kind::AKP153), ["Ajazz AKP153"]);
+    }
+
+    #[test]
+    fn numbers_are_stable_across_restarts() {
+        let mut known = Known::default();
+        let before = connect(&mut known, &["c", "a", "b"], &Kind::AKP153);
+
+        // Devices connect in a different order after restart, with known devices loaded from the file
+        let mut restarted: Known =
+            serde_json::from_slice(&serde_json::to_vec(&known).unwrap()).unwrap();
+
+        assert!(!restarted.remember_kind("b", &Kind::AKP153));
+        assert_eq!(restarted.name("b", &Kind::AKP153), before[2]);
+        assert_eq!(restarted.name("c", &Kind::AKP153), before[0]);
+        assert_eq!(restarted.name("a", &Kind::AKP153), before[1]);
+    }
+}
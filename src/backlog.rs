@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// How long writes are summed up for before the device is checked
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Share of the window spent writing images above which device can't keep up
+const BACKLOG_RATIO: f32 = 0.8;
+
+/// Share of the window spent writing images below which device keeps up easily
+const CLEAR_RATIO: f32 = 0.4;
+
+/// Windows in a row device has to be behind for before quality is lowered
+const BACKLOG_WINDOWS: u32 = 3;
+
+/// Windows in a row device has to keep up for before quality is raised back
+const CLEAR_WINDOWS: u32 = 10;
+
+/// How much JPEG quality is lowered or raised at once
+const QUALITY_STEP: u8 = 10;
+
+/// How much JPEG quality can be lowered in total
+const MAX_REDUCTION: u8 = 40;
+
+/// Time spent writing images to the device, and how much its quality is lowered because of that
+struct Load {
+    window_started: Instant,
+    busy: Duration,
+    backlog_windows: u32,
+    clear_windows: u32,
+    reduction: u8,
+}
+
+impl Load {
+    fn new() -> Self {
+        Self {
+            window_started: Instant::now(),
+            busy: Duration::ZERO,
+            backlog_windows: 0,
+            clear_windows: 0,
+            reduction: 0,
+        }
+    }
+}
+
+/// Loads by device id
+static LOADS: LazyLock<Mutex<HashMap<String, Load>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts time spent writing an image to the device, lowering or restoring its quality once the window is over
+///
+/// Changes are spaced by the windows device has to stay behind or keep up for, so quality can't jump back and forth
+pub async fn record_write(id: &str, elapsed: Duration) {
+    let mut loads = LOADS.lock().await;
+    let load = loads.entry(id.to_string()).or_insert_with(Load::new);
+
+    load.busy += elapsed;
+
+    let window = load.window_started.elapsed();
+
+    if window < WINDOW {
+        return;
+    }
+
+    let ratio = load.busy.as_secs_f32() / window.as_secs_f32();
+
+    load.window_started = Instant::now();
+    load.busy = Duration::ZERO;
+
+    if ratio > BACKLOG_RATIO {
+        load.backlog_windows += 1;
+        load.clear_windows = 0;
+    } else if ratio < CLEAR_RATIO {
+        // Idle time counts as many windows device kept up for
+        load.clear_windows += (window.as_secs_f32() / WINDOW.as_secs_f32()) as u32;
+        load.backlog_windows = 0;
+    } else {
+        load.backlog_windows = 0;
+        load.clear_windows = 0;
+    }
+
+    if load.backlog_windows >= BACKLOG_WINDOWS && load.reduction < MAX_REDUCTION {
+        load.reduction += QUALITY_STEP;
+        load.backlog_windows = 0;
+
+        log::info!(
+            "Device {} can't keep up with images, lowering JPEG quality, it is lowered by {} now",
+            id,
+            load.reduction
+        );
+    } else if load.clear_windows >= CLEAR_WINDOWS && load.reduction > 0 {
+        load.reduction -= QUALITY_STEP;
+        load.clear_windows = 0;
+
+        log::info!(
+            "Device {} keeps up with images again, raising JPEG quality, it is lowered by {} now",
+            id,
+            load.reduction
+        );
+    }
+}
+
+/// Returns how much JPEG quality of the device images is lowered, because device can't keep up with them
+pub async fn quality_reduction(id: &str) -> u8 {
+    LOADS
+        .lock()
+        .await
+        .get(id)
+        .map(|load| load.reduction)
+        .unwrap_or(0)
+}
+
+/// Forgets load of the device, so it starts with the configured quality when it's connected again
+pub async fn forget(id: &str) {
+    LOADS.lock().await.remove(id);
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+/// Default limit for all the cached images, in bytes
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Keeps encoded images sent to the devices, so they can be written again without OpenDeck,
+/// evicting least recently used images once total size goes above the limit
+pub struct ImageCache {
+    images: LruCache<(String, u8), Vec<u8>>,
+    /// Devices that lost some of their images to eviction
+    evicted: HashSet<String>,
+}
+
+impl ImageCache {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            images: LruCache::new(limit),
+            evicted: HashSet::new(),
+        }
+    }
+
+    /// Changes the limit, evicting images if needed
+    pub fn set_limit(&mut self, limit: usize) {
+        let evicted = self.images.set_limit(limit);
+        self.remember_evicted(evicted);
+    }
+
+    /// Stores image for the device key
+    pub fn insert(&mut self, id: &str, key: u8, data: Vec<u8>) {
+        let size = data.len();
+        let evicted = self.images.insert((id.to_string(), key), data, size);
+
+        self.remember_evicted(evicted);
+    }
+
+    /// Returns image of the device key
+    pub fn get(&mut self, id: &str, key: u8) -> Option<Vec<u8>> {
+        self.images.get(&(id.to_string(), key))
+    }
+
+    /// Forgets image of the device key
+    pub fn remove(&mut self, id: &str, key: u8) {
+        self.images.remove(&(id.to_string(), key));
+    }
+
+    /// Forgets all the images of the device
+    pub fn remove_device(&mut self, id: &str) {
+        self.images.remove_where(|(entry_id, _)| entry_id == id);
+        self.evicted.remove(id);
+    }
+
+    /// Returns all the images of the device as (key, data) pairs, and whether some images were evicted
+    pub fn device_images(&mut self, id: &str) -> (Vec<(u8, Vec<u8>)>, bool) {
+        let images = self
+            .images
+            .get_where(|(entry_id, _)| entry_id == id)
+            .into_iter()
+            .map(|((_, key), data)| (key, data))
+            .collect();
+
+        (images, self.evicted.contains(id))
+    }
+
+    /// Returns size of the cached images of the device, in bytes
+    pub fn usage(&self, id: &str) -> usize {
+        self.images.usage_where(|(entry_id, _)| entry_id == id)
+    }
+
+    /// Returns size of all the cached images, in bytes
+    pub fn total_usage(&self) -> usize {
+        self.images.usage()
+    }
+
+    /// Evicts least recently used images until total size is at most `size` bytes
+    pub fn shrink(&mut self, size: usize) {
+        let evicted = self.images.shrink(size);
+        self.remember_evicted(evicted);
+    }
+
+    fn remember_evicted(&mut self, evicted: Vec<(String, u8)>) {
+        for (id, key) in evicted {
+            log::info!(
+                "Image cache is full, evicted image of key {} of {}",
+                key,
+                id
+            );
+            self.evicted.insert(id);
+        }
+    }
+}
+
+/// Least recently used cache bounded by total size of the values in bytes, counting hits and misses
+pub struct LruCache<K, V> {
+    limit: usize,
+    used: usize,
+    tick: u64,
+    /// Values with their size and when they were used last time
+    entries: HashMap<K, (V, usize, u64)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Changes the limit, evicting values if needed, returns keys of the evicted values
+    pub fn set_limit(&mut self, limit: usize) -> Vec<K> {
+        self.limit = limit;
+        self.evict()
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+
+        match self.entries.get_mut(key) {
+            Some((value, _, last_used)) => {
+                *last_used = self.tick;
+                self.hits += 1;
+
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+
+                None
+            }
+        }
+    }
+
+    /// Returns all the values with keys matching the predicate, marking them as used
+    pub fn get_where(&mut self, predicate: impl Fn(&K) -> bool) -> Vec<(K, V)> {
+        self.tick += 1;
+
+        self.entries
+            .iter_mut()
+            .filter(|(key, _)| predicate(key))
+            .map(|(key, (value, _, last_used))| {
+                *last_used = self.tick;
+                (key.clone(), value.clone())
+            })
+            .collect()
+    }
+
+    /// Stores value of the given size in bytes, returns keys of the values evicted to make room for it
+    pub fn insert(&mut self, key: K, value: V, size: usize) -> Vec<K> {
+        self.tick += 1;
+        self.used += size;
+
+        if let Some((_, previous, _)) = self.entries.insert(key, (value, size, self.tick)) {
+            self.used -= previous;
+        }
+
+        self.evict()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, size, _) = self.entries.remove(key)?;
+        self.used -= size;
+
+        Some(value)
+    }
+
+    /// Removes all the values with keys matching the predicate
+    pub fn remove_where(&mut self, predicate: impl Fn(&K) -> bool) {
+        let used = &mut self.used;
+
+        self.entries.retain(|key, (_, size, _)| {
+            let keep = !predicate(key);
+
+            if !keep {
+                *used -= *size;
+            }
+
+            keep
+        });
+    }
+
+    /// Returns number of hits and misses
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Returns size of all the values, in bytes
+    pub fn usage(&self) -> usize {
+        self.used
+    }
+
+    /// Returns size of the values with keys matching the predicate, in bytes
+    pub fn usage_where(&self, predicate: impl Fn(&K) -> bool) -> usize {
+        self.entries
+            .iter()
+            .filter(|(key, _)| predicate(key))
+            .map(|(_, (_, size, _))| size)
+            .sum()
+    }
+
+    /// Evicts least recently used values until total size is at most `size` bytes, returns their keys
+    pub fn shrink(&mut self, size: usize) -> Vec<K> {
+        let mut evicted = Vec::new();
+
+        while self.used > size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+
+    fn evict(&mut self) -> Vec<K> {
+        self.shrink(self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &mut ImageCache, id: &str) -> Vec<u8> {
+        let mut keys: Vec<u8> = cache
+            .device_images(id)
+            .0
+            .iter()
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort();
+
+        keys
+    }
+
+    #[test]
+    fn least_recently_used_image_is_evicted() {
+        let mut cache = ImageCache::new(30);
+
+        cache.insert("a", 0, vec![0; 10]);
+        cache.insert("a", 1, vec![0; 10]);
+        cache.insert("a", 2, vec![0; 10]);
+
+        // Replacing the oldest image makes the next one the least recently used
+        cache.insert("a", 0, vec![0; 10]);
+
+        cache.insert("b", 0, vec![0; 10]);
+
+        assert_eq!(keys(&mut cache, "a"), [0, 2]);
+        assert_eq!(cache.total_usage(), 30);
+        assert!(cache.device_images("a").1);
+        assert!(!cache.device_images("b").1);
+    }
+
+    #[test]
+    fn replaced_image_is_not_counted_twice() {
+        let mut cache = ImageCache::new(30);
+
+        cache.insert("a", 0, vec![0; 10]);
+        cache.insert("a", 0, vec![0; 20]);
+        cache.insert("a", 1, vec![0; 10]);
+
+        assert_eq!(cache.usage("a"), 30);
+        assert_eq!(keys(&mut cache, "a"), [0, 1]);
+        assert!(!cache.device_images("a").1);
+    }
+
+    #[test]
+    fn lowering_limit_evicts_oldest_images() {
+        let mut cache = ImageCache::new(100);
+
+        for key in 0..5 {
+            cache.insert("a", key, vec![0; 10]);
+        }
+
+        cache.set_limit(20);
+
+        assert_eq!(keys(&mut cache, "a"), [3, 4]);
+        assert_eq!(cache.total_usage(), 20);
+    }
+
+    #[test]
+    fn image_over_limit_is_not_kept() {
+        let mut cache = ImageCache::new(10);
+
+        cache.insert("a", 0, vec![0; 20]);
+
+        assert!(keys(&mut cache, "a").is_empty());
+        assert_eq!(cache.total_usage(), 0);
+    }
+
+    #[test]
+    fn removing_device_forgets_its_evictions() {
+        let mut cache = ImageCache::new(10);
+
+        cache.insert("a", 0, vec![0; 10]);
+        cache.insert("a", 1, vec![0; 10]);
+        assert!(cache.device_images("a").1);
+
+        cache.remove_device("a");
+
+        assert_eq!(cache.device_images("a"), (vec![], false));
+        assert_eq!(cache.total_usage(), 0);
+    }
+
+    #[test]
+    fn shrinking_marks_devices_that_lost_images() {
+        let mut cache = ImageCache::new(100);
+
+        cache.insert("a", 0, vec![0; 10]);
+        cache.insert("b", 0, vec![0; 10]);
+        cache.shrink(10);
+
+        assert!(cache.device_images("a").1);
+        assert!(!cache.device_images("b").1);
+        assert_eq!(cache.total_usage(), 10);
+    }
+
+    #[test]
+    fn lru_cache_evicts_least_recently_used_values() {
+        let mut cache = LruCache::new(30);
+
+        cache.insert("a", 1, 10);
+        cache.insert("b", 2, 10);
+        cache.insert("c", 3, 10);
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        assert_eq!(cache.insert("d", 4, 10), ["b"]);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.usage(), 30);
+        assert_eq!(cache.stats(), (1, 1));
+    }
+
+    #[test]
+    fn lru_cache_shrinks_to_size() {
+        let mut cache = LruCache::new(100);
+
+        for key in 0..5 {
+            cache.insert(key, key, 10);
+        }
+
+        assert_eq!(cache.shrink(25), [0, 1, 2]);
+        assert_eq!(cache.usage(), 20);
+        assert!(cache.shrink(25).is_empty());
+
+        // Limit stays as it was, so it only applies to the next inserts
+        cache.insert(5, 5, 60);
+        assert_eq!(cache.usage(), 80);
+    }
+}
@@ -1,93 +1,788 @@
 use data_url::DataUrl;
-use image::load_from_memory_with_format;
-use mirajazz::{device::Device, error::MirajazzError, state::DeviceStateUpdate};
-use openaction::{OUTBOUND_EVENT_MANAGER, SetImageEvent};
+use image::{DynamicImage, imageops::FilterType, load_from_memory_with_format};
+use mirajazz::{
+    device::Device, error::MirajazzError, state::DeviceStateUpdate, types::ImageFormat,
+};
+use openaction::SetImageEvent;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::Infallible,
+    mem::Discriminant,
+    sync::{Arc, LazyLock, atomic::Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    DEVICES, TOKENS,
-    inputs::opendeck_to_device,
+    BRIGHTNESS, DEVICES, IMAGE_CACHE, KINDS, RESET_TOKENS, SETTINGS, TOKENS, aliases, backlog, eco,
+    encode::{
+        self, cache_button_data, can_pass_through, encode_source, set_button_data, set_button_image,
+    },
+    fade,
+    inputs::{LOW_LATENCY, device_to_opendeck, opendeck_to_device},
+    lock,
     mappings::{
-        COL_COUNT, CandidateDevice, ENCODER_COUNT, KEY_COUNT, Kind, ROW_COUNT,
-        get_image_format_for_key,
+        CandidateDevice, ENCODER_COUNT, KEY_COUNT, Kind, get_image_format_for_key,
+        get_wallpaper_size, get_wallpaper_tile,
     },
+    outbound::{self, OpenDeck, Outbound},
+    permissions, pipeline,
+    power::{disable_autosuspend, is_resume_error},
+    pressed,
+    ratelimit::{self, RateLimiter},
+    registration,
+    screen::Screen,
+    stats, status, trace, watchdog,
 };
 
-/// Initializes a device and listens for events
-pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
-    log::info!("Running device task for {:?}", candidate);
+/// Brightness used for devices OpenDeck didn't set brightness for yet
+const DEFAULT_BRIGHTNESS: u8 = 50;
+
+/// How many times keys blink when device is identified
+const IDENTIFY_BLINKS: usize = 3;
+const IDENTIFY_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Delay before reconnecting to the device after a reset
+const RESET_DELAY: Duration = Duration::from_secs(1);
+
+/// How many times device init is attempted before device task gives up
+const INIT_ATTEMPTS: usize = 3;
+const INIT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Device is reset after sending this many unparseable frames in a row, because its reports are likely shifted
+const BAD_FRAME_LIMIT: usize = 5;
+
+/// Device is reported as not accepting images after this many failed writes in a row
+pub const WRITE_FAILURE_LIMIT: usize = 3;
+
+/// Image writes that failed in a row, by device id
+static WRITE_FAILURES: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Device id and kind of the error
+type ErrorKey = (String, Discriminant<MirajazzError>);
+
+/// Device errors logged recently, by device id and kind of the error
+static ERROR_LOGS: LazyLock<Mutex<RateLimiter<ErrorKey>>> =
+    LazyLock::new(|| Mutex::new(RateLimiter::new(ratelimit::BURST, ratelimit::WINDOW)));
+
+/// Locks serializing image transfers and control commands, by device id
+static TRANSFER_LOCKS: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Locks the device for a transfer or a command, if its kind can't interleave them
+async fn lock_transfers(id: &str, kind: &Kind) -> Option<OwnedMutexGuard<()>> {
+    if kind.interleaves_commands() {
+        return None;
+    }
+
+    let lock = TRANSFER_LOCKS
+        .lock()
+        .await
+        .entry(id.to_string())
+        .or_default()
+        .clone();
+
+    Some(lock.lock_owned().await)
+}
+
+/// When images can be written again after brightness change, by device id
+static BRIGHTNESS_SETTLED: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Sets brightness, waiting for the image transfer in progress on devices that can't interleave them
+pub async fn set_brightness(
+    id: &str,
+    device: &Device,
+    brightness: u8,
+) -> Result<(), MirajazzError> {
+    let kind = device_kind(id, device).await;
+    let brightness = SETTINGS.read().await.device_brightness(id, brightness);
+    let _transfer = lock_transfers(id, &kind).await;
+
+    device.set_brightness(brightness).await?;
+    hold_images(id, &kind).await;
+
+    Ok(())
+}
+
+/// Holds images back after brightness change, on kinds that need time for that
+async fn hold_images(id: &str, kind: &Kind) {
+    if !kind.brightness_settle_time().is_zero() {
+        BRIGHTNESS_SETTLED.lock().await.insert(
+            id.to_string(),
+            Instant::now() + kind.brightness_settle_time(),
+        );
+    }
+}
+
+/// Waits until device is ready for images after brightness change, on kinds that need time for that
+async fn wait_for_brightness(id: &str) {
+    let Some(settled) = BRIGHTNESS_SETTLED.lock().await.remove(id) else {
+        return;
+    };
+
+    if settled > Instant::now() {
+        log::debug!("Waiting for brightness of {} to settle", id);
+        tokio::time::sleep_until(settled.into()).await;
+    }
+}
+
+/// How many times a flush interrupted by a signal is attempted
+const FLUSH_ATTEMPTS: usize = 3;
+
+/// Flush that takes longer than this is stuck, like when device is unplugged in the middle of a write
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks if write was interrupted by a signal, like SIGTERM arriving in the middle of it
+fn is_interrupted(err: &MirajazzError) -> bool {
+    let MirajazzError::HidError(async_hid::HidError::Other(err)) = err else {
+        return false;
+    };
+
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::Interrupted)
+}
+
+/// Checks if write was given up on, because it took too long
+fn is_timed_out(err: &MirajazzError) -> bool {
+    let MirajazzError::HidError(async_hid::HidError::Other(err)) = err else {
+        return false;
+    };
+
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+}
+
+/// Flushes written images, sending all of them again if writing was interrupted by a signal
+///
+/// Images are only dropped from the queue after all of them are sent, so retrying never leaves a key half-written.
+/// Short writes can't be caught here, because HID layer doesn't report them
+async fn flush(device: &impl Screen) -> Result<(), MirajazzError> {
+    let mut attempt = 1;
+
+    loop {
+        // Dropping the write is safe, images stay queued until all of them are sent
+        let Ok(result) = tokio::time::timeout(FLUSH_TIMEOUT, device.flush()).await else {
+            log::warn!(
+                "Flush took longer than {:?}, giving up on it",
+                FLUSH_TIMEOUT
+            );
+
+            let err = std::io::Error::new(std::io::ErrorKind::TimedOut, "flush timed out");
+            return Err(async_hid::HidError::from(err).into());
+        };
+
+        match result {
+            Err(err) if attempt < FLUSH_ATTEMPTS && is_interrupted(&err) => {
+                log::warn!("Flush was interrupted, retrying: {}", err);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
-    // Wrap in a closure so we can use `?` operator
-    let device = async || -> Result<Device, MirajazzError> {
-        let device = connect(&candidate).await?;
+/// Encodes image and shows it on the key right away, caching it like the images from OpenDeck
+pub async fn show_image(
+    id: &str,
+    device: &Device,
+    key: u8,
+    format: ImageFormat,
+    image: DynamicImage,
+) -> Result<(), MirajazzError> {
+    wait_for_brightness(id).await;
 
-        device.set_brightness(50).await?;
-        device.clear_all_button_images().await?;
-        device.flush().await?;
+    let transfer = lock_transfers(id, &device_kind(id, device).await).await;
 
-        Ok(device)
-    }()
+    set_button_image(id, device, key, format, image).await?;
+    let result = flush(device).await;
+
+    drop(transfer);
+
+    result
+}
+
+/// Writes already encoded image to the key and shows it right away, without caching it
+pub async fn write_key(
+    id: &str,
+    device: &Device,
+    key: u8,
+    data: &[u8],
+) -> Result<(), MirajazzError> {
+    wait_for_brightness(id).await;
+
+    let transfer = lock_transfers(id, &device_kind(id, device).await).await;
+
+    device.write_image(key, data).await?;
+    let result = flush(device).await;
+
+    drop(transfer);
+
+    result
+}
+
+/// Returns brightness device is brought up with
+///
+/// Devices don't allow to read current brightness back, so use the last value OpenDeck has set instead,
+/// which it could send before device was connected
+pub async fn initial_brightness(id: &str) -> u8 {
+    let brightness = BRIGHTNESS
+        .read()
+        .await
+        .get(id)
+        .copied()
+        .unwrap_or(DEFAULT_BRIGHTNESS);
+
+    SETTINGS.read().await.device_brightness(id, brightness)
+}
+
+/// Connects to the device and brings it to the initial state
+async fn init_device(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
+    if SETTINGS.read().await.disable_autosuspend {
+        disable_autosuspend(&candidate.dev).unwrap_or_else(|err| {
+            log::warn!(
+                "Unable to disable autosuspend for {}, continuing anyway: {}",
+                candidate.id,
+                err
+            )
+        });
+    }
+
+    let device = connect(candidate).await?;
+
+    let brightness = initial_brightness(&candidate.id).await;
+
+    log::info!("Setting brightness of {} to {}", candidate.id, brightness);
+    device.set_brightness(brightness).await?;
+    hold_images(&candidate.id, &candidate.kind).await;
+    device.clear_all_button_images().await?;
+    flush(&device).await?;
+
+    if candidate.kind.shows_boot_logo() && !aliases::is_logo_cleared(&candidate.id).await {
+        clear_boot_logo(&candidate.id, &device).await?;
+    }
+
+    let commands = SETTINGS
+        .read()
+        .await
+        .extended_init
+        .get(&candidate.id)
+        .cloned()
+        .unwrap_or_default();
+
+    if !commands.is_empty() {
+        extended_init(&candidate.id, &device, commands).await?;
+    }
+
+    if !SETTINGS
+        .read()
+        .await
+        .skip_test_frame
+        .contains(&candidate.id)
+    {
+        write_test_frame(candidate, &device).await?;
+    }
+
+    Ok(device)
+}
+
+/// Writes blank frame to the first key, so device with a hung panel controller fails init instead of being registered
+///
+/// Devices don't acknowledge images, so only the write itself can be checked
+async fn write_test_frame(
+    candidate: &CandidateDevice,
+    device: &Device,
+) -> Result<(), MirajazzError> {
+    let position = candidate.kind.matrix_position(0);
+    let blank = pipeline::blank(&get_image_format_for_key(&candidate.kind, position))?;
+
+    let result = async {
+        device
+            .write_image(opendeck_to_device(position), &blank)
+            .await?;
+        flush(device).await
+    }
     .await;
 
-    let device: Device = match device {
-        Ok(device) => device,
-        Err(err) => {
-            handle_error(&candidate.id, err).await;
+    if let Err(err) = &result {
+        log::error!(
+            "Device {} didn't accept the test frame: {}",
+            candidate.id,
+            err
+        );
+    }
+
+    result
+}
+
+/// Sends extra init commands from settings, for clones that ACK images but keep the keys black until they get them
+///
+/// Devices don't report whether the panel is active, so there is no way to tell when they are needed
+async fn extended_init(
+    id: &str,
+    device: &Device,
+    commands: Vec<Vec<u8>>,
+) -> Result<(), MirajazzError> {
+    log::info!(
+        "Sending {} extended init commands to {}",
+        commands.len(),
+        id
+    );
+
+    for command in commands {
+        log::debug!("Sending extended init command {:02X?} to {}", command, id);
+
+        // Commands come without the report id
+        let mut buf = vec![0x00];
+        buf.extend(command);
+
+        device.write_extended_data(&mut buf).await?;
+    }
+
+    flush(device).await
+}
+
+/// Commits clearing of all the keys, which "v1" devices don't do on their own, so boot logo goes away
+///
+/// It makes keys flicker, so it's only done once per device
+async fn clear_boot_logo(id: &str, device: &Device) -> Result<(), MirajazzError> {
+    log::info!("Clearing boot logo of {}", id);
+
+    // Same STP command `flush` sends to commit images
+    let mut buf = vec![0x00, 0x43, 0x52, 0x54, 0x00, 0x00, 0x53, 0x54, 0x50];
+    device.write_extended_data(&mut buf).await?;
+
+    aliases::set_logo_cleared(id).await;
+
+    Ok(())
+}
+
+/// Inits the device, retrying a few times, because devices often aren't ready right after being plugged in
+async fn init_device_with_retries(
+    candidate: &CandidateDevice,
+    token: &CancellationToken,
+) -> Option<Device> {
+    for attempt in 1..=INIT_ATTEMPTS {
+        match init_device(candidate).await {
+            Ok(device) => return Some(device),
+            Err(err) => {
+                log::warn!(
+                    "Attempt {} of {} to init {} failed: {}",
+                    attempt,
+                    INIT_ATTEMPTS,
+                    candidate.id,
+                    err
+                );
 
+                if permissions::denied_at_boot(&err) {
+                    log::warn!(
+                        "Access to {} was denied right after boot, likely udev didn't apply permissions yet, retrying once they change",
+                        candidate.id
+                    );
+
+                    if permissions::wait_for_change(&candidate.dev, token).await {
+                        continue;
+                    }
+
+                    log::warn!(
+                        "Permissions of {} didn't change, replugging the device should fix them",
+                        candidate.id
+                    );
+                }
+
+                handle_error(&candidate.id, err).await;
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(INIT_RETRY_DELAY) => {},
+            _ = token.cancelled() => return None
+        };
+    }
+
+    None
+}
+
+/// Initializes a device and listens for events
+pub async fn device_task(mut candidate: CandidateDevice, token: CancellationToken) {
+    log::info!("Running device task for {:?}", candidate);
+
+    // Held until device task finishes
+    let _lock = match lock::acquire(&candidate.id) {
+        Ok(lock) => lock,
+        Err(holder) => {
             log::error!(
-                "Had error during device init, finishing device task: {:?}",
-                candidate
+                "Device {} is used by another plugin instance ({}), skipping it",
+                candidate.id,
+                holder
             );
 
+            TOKENS.write().await.remove(&candidate.id);
+
             return;
         }
     };
 
-    log::info!("Registering device {}", candidate.id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound
-            .register_device(
-                candidate.id.clone(),
-                candidate.kind.human_name(),
-                ROW_COUNT as u8,
-                COL_COUNT as u8,
-                ENCODER_COUNT as u8,
-                0,
-            )
+    let detected = candidate.kind.clone();
+    let mut registered = false;
+    let mut resumed = false;
+
+    loop {
+        if SETTINGS.read().await.paused_devices.contains(&candidate.id) {
+            log::info!("Device {} is paused", candidate.id);
+
+            if registered && SETTINGS.read().await.deregister_paused {
+                registration::deregister(&candidate.id, &OpenDeck).await;
+                registered = false;
+            }
+
+            // Settings are checked once in a while, because resuming right away is not important
+            while SETTINGS.read().await.paused_devices.contains(&candidate.id) {
+                tokio::select! {
+                    _ = tokio::time::sleep(RESET_DELAY) => {},
+                    _ = token.cancelled() => break
+                };
+            }
+
+            if token.is_cancelled() {
+                break;
+            }
+
+            log::info!("Resuming device {}", candidate.id);
+            resumed = true;
+        }
+
+        let kind = match SETTINGS.read().await.kind_override(&candidate.id) {
+            Some(kind) => {
+                log::warn!(
+                    "Kind of {} is overridden in settings, treating {:?} as {:?}",
+                    candidate.id,
+                    detected,
+                    kind
+                );
+
+                kind
+            }
+            None => detected.clone(),
+        };
+
+        // Images encoded for the other kind would be wrong, so get all of them again
+        let kind_changed = kind != candidate.kind;
+
+        if kind_changed {
+            IMAGE_CACHE.lock().await.remove_device(&candidate.id);
+            candidate.kind = kind;
+        }
+
+        let delay = watchdog::connecting(&candidate.id).await;
+
+        if !delay.is_zero() {
+            log::info!("Waiting {:?} before connecting to {}", delay, candidate.id);
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = token.cancelled() => break
+            };
+        }
+
+        let Some(device) = init_device_with_retries(&candidate, &token).await else {
+            log::error!(
+                "Unable to init device, finishing device task: {:?}",
+                candidate
+            );
+
+            TOKENS.write().await.remove(&candidate.id);
+
+            // Device could have been connected before the reset, so drop what was kept for it
+            break;
+        };
+
+        if !registered {
+            if let Some(primary) = SETTINGS.read().await.mirror_primary(&candidate.id) {
+                log::info!(
+                    "Not registering device {}, it mirrors {}",
+                    candidate.id,
+                    primary
+                );
+            } else {
+                registration::register(&candidate.id, &candidate.kind, &OpenDeck).await;
+            }
+
+            registered = true;
+        } else if kind_changed {
+            OpenDeck.rerender_images(&candidate.id).await.ok();
+        } else {
+            restore_images(&candidate.id, &device).await;
+
+            // Images sent while device was paused were dropped, so get the current ones
+            if resumed {
+                OpenDeck.rerender_images(&candidate.id).await.ok();
+            }
+        }
+
+        resumed = false;
+
+        KINDS
+            .write()
             .await
-            .unwrap();
-    }
+            .insert(candidate.id.clone(), candidate.kind.clone());
+        WRITE_FAILURES.lock().await.remove(&candidate.id);
+        DEVICES.write().await.insert(candidate.id.clone(), device);
+        stats::connected(&candidate.id).await;
 
-    DEVICES.write().await.insert(candidate.id.clone(), device);
+        let reset = CancellationToken::new();
 
-    tokio::select! {
-        _ = device_events_task(&candidate) => {},
-        _ = token.cancelled() => {}
-    };
+        RESET_TOKENS
+            .write()
+            .await
+            .insert(candidate.id.clone(), reset.clone());
+
+        let connected = Instant::now();
+
+        tokio::select! {
+            _ = device_events_task(&candidate, &OpenDeck) => {},
+            _ = status::status_task(&candidate.id) => {},
+            _ = reset.cancelled() => {},
+            _ = token.cancelled() => {}
+        };
+
+        stats::disconnected(&candidate.id).await;
+        backlog::forget(&candidate.id).await;
+
+        if connected.elapsed() >= watchdog::STABLE_TIME {
+            watchdog::stable(&candidate.id).await;
+        }
+
+        RESET_TOKENS.write().await.remove(&candidate.id);
+
+        if token.is_cancelled() || !reset.is_cancelled() {
+            break;
+        }
+
+        if SETTINGS.read().await.paused_devices.contains(&candidate.id) {
+            log::info!("Pausing device {}, closing it", candidate.id);
+        } else {
+            log::warn!("Resetting device {}", candidate.id);
+        }
+
+        DEVICES.write().await.remove(&candidate.id);
+
+        tokio::select! {
+            _ = tokio::time::sleep(RESET_DELAY) => {},
+            _ = token.cancelled() => break
+        };
+    }
 
     log::info!("Shutting down device {:?}", candidate);
 
+    let mut cache = IMAGE_CACHE.lock().await;
+    log::info!(
+        "Dropping {} bytes of cached images for {}",
+        cache.usage(&candidate.id),
+        candidate.id
+    );
+    cache.remove_device(&candidate.id);
+    drop(cache);
+
+    KINDS.write().await.remove(&candidate.id);
+
     if let Some(device) = DEVICES.read().await.get(&candidate.id) {
         device.shutdown().await.ok();
     }
 
+    // Device stays plugged in, so release it here instead of waiting for it to be disconnected
+    if SETTINGS
+        .read()
+        .await
+        .excluded_devices
+        .contains(&candidate.id)
+    {
+        DEVICES.write().await.remove(&candidate.id);
+        registration::deregister(&candidate.id, &OpenDeck).await;
+
+        log::info!("Released excluded device {}", candidate.id);
+    }
+
     log::info!("Device task finished for {:?}", candidate);
 }
 
+/// Returns kind device is treated as, which can differ from the one matching its VID and PID
+pub async fn device_kind(id: &str, device: &Device) -> Kind {
+    match KINDS.read().await.get(id) {
+        Some(kind) => kind.clone(),
+        None => Kind::from_vid_pid(device.vid, device.pid).unwrap(), // Safe to unwrap here, because device is already filtered
+    }
+}
+
+/// Brings device back from sleep with the brightness and images it had, without reconnecting to it
+pub async fn wake(id: &String, device: &Device) -> Result<(), MirajazzError> {
+    let kind = device_kind(id, device).await;
+    let brightness = initial_brightness(id).await;
+
+    let transfer = lock_transfers(id, &kind).await;
+    device.set_brightness(brightness).await?;
+    hold_images(id, &kind).await;
+    drop(transfer);
+
+    restore_images(id, device).await;
+
+    Ok(())
+}
+
+/// Writes cached images to the device after reset or sleep, asking OpenDeck for the images if some of them are missing
+async fn restore_images(id: &String, device: &Device) {
+    let (images, evicted) = IMAGE_CACHE.lock().await.device_images(id);
+
+    if evicted {
+        log::info!("Some images of {} were evicted, requesting all of them", id);
+        OpenDeck.rerender_images(id).await.ok();
+
+        return;
+    }
+
+    // Another device could take over the id, like a different kind plugged into the same port,
+    // so only images of the key sizes it expects are written back
+    let kind = device_kind(id, device).await;
+    let (images, stale) = split_stale(&kind, images);
+
+    log::info!("Restoring {} images of {}", images.len(), id);
+
+    wait_for_brightness(id).await;
+
+    for (key, data) in images {
+        device.write_image(key, &data).await.ok();
+    }
+
+    flush(device).await.ok();
+
+    if !stale.is_empty() {
+        log::info!(
+            "Discarding {} images of {} encoded for another kind, requesting them again",
+            stale.len(),
+            id
+        );
+
+        let mut cache = IMAGE_CACHE.lock().await;
+
+        for (key, _) in stale {
+            cache.remove(id, key);
+        }
+
+        drop(cache);
+
+        OpenDeck.rerender_images(id).await.ok();
+    }
+}
+
+/// Cached images of the device, by 0-based device key
+type KeyImages = Vec<(u8, Vec<u8>)>;
+
+/// Splits cached images into the ones encoded for the keys of the kind, and the ones encoded for another kind
+fn split_stale(kind: &Kind, images: KeyImages) -> (KeyImages, KeyImages) {
+    images.into_iter().partition(|(key, data)| {
+        let position = device_to_opendeck(*key as usize + 1) as u8; // Cache keys are 0-based
+
+        pipeline::fits(&get_image_format_for_key(kind, position), data)
+    })
+}
+
+/// Checks if device still accepts commands, returning how long it took
+///
+/// Uses keep alive command, which is harmless for the device. Devices don't acknowledge it,
+/// so this is the time it took to write the command, not a true round trip
+pub async fn ping(device: &Device) -> Result<Duration, MirajazzError> {
+    let start = Instant::now();
+
+    device.keep_alive().await?;
+
+    Ok(start.elapsed())
+}
+
+/// Blinks all the keys of the device, so user can find which physical device has this id
+///
+/// Device is looked up for every change of brightness, so it can be reset or unplugged while blinking
+pub async fn identify(id: &str) -> Result<(), MirajazzError> {
+    log::info!("Identifying device {}", id);
+
+    let brightness = BRIGHTNESS
+        .read()
+        .await
+        .get(id)
+        .copied()
+        .unwrap_or(DEFAULT_BRIGHTNESS);
+
+    for _ in 0..IDENTIFY_BLINKS {
+        for level in [0, 100] {
+            if !blink(id, level).await? {
+                log::info!("Device {} is gone, stopping identifying it", id);
+                return Ok(());
+            }
+
+            tokio::time::sleep(IDENTIFY_INTERVAL).await;
+        }
+    }
+
+    blink(id, brightness).await?;
+
+    Ok(())
+}
+
+/// Sets brightness of the device, returning false if it's not connected anymore
+async fn blink(id: &str, brightness: u8) -> Result<bool, MirajazzError> {
+    match DEVICES.read().await.get(id) {
+        Some(device) => set_brightness(id, device, brightness).await.map(|_| true),
+        None => Ok(false),
+    }
+}
+
 /// Handles errors, returning true if should continue, returning false if an error is fatal
 pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
-    log::error!("Device {} error: {}", id, err);
+    let check = ERROR_LOGS
+        .lock()
+        .await
+        .check((id.clone(), std::mem::discriminant(&err)));
+
+    if let Some(suppressed) = check {
+        log::error!(
+            "Device {} error: {}{}",
+            id,
+            err,
+            ratelimit::suppressed_suffix(suppressed)
+        );
+    }
 
     // Some errors are not critical and can be ignored without sending disconnected event
     if matches!(err, MirajazzError::ImageError(_) | MirajazzError::BadData) {
         return true;
     }
 
-    log::info!("Deregistering device {}", id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound.deregister_device(id.clone()).await.unwrap();
+    // Device is still there, but stopped responding after USB autosuspend or in the middle of a write,
+    // so reconnect to it. Unplugged device fails to reconnect and is removed then
+    let stuck = if is_resume_error(&err) {
+        Some("failed to resume from autosuspend")
+    } else if is_timed_out(&err) {
+        Some("got stuck writing images")
+    } else {
+        None
+    };
+
+    if let Some(reason) = stuck {
+        let resets = RESET_TOKENS.read().await;
+
+        if let Some(reset) = resets.get(id) {
+            log::warn!("Device {} {}, resetting", id, reason);
+            reset.cancel();
+
+            return false;
+        }
     }
 
+    log::info!("Deregistering device {}", id);
+    registration::unplugged(id).await;
+
     log::info!("Cancelling tasks for device {}", id);
     if let Some(token) = TOKENS.read().await.get(id) {
         token.cancel();
@@ -105,6 +800,7 @@ pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzErro
     let result = Device::connect(
         &candidate.dev,
         candidate.kind.protocol_version(),
+        // Device reports the whole matrix, even if some keys are absent
         KEY_COUNT,
         ENCODER_COUNT,
     )
@@ -121,7 +817,10 @@ pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzErro
 }
 
 /// Handles events from device to OpenDeck
-async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
+async fn device_events_task(
+    candidate: &CandidateDevice,
+    outbound: &impl Outbound,
+) -> Result<(), MirajazzError> {
     log::info!("Connecting to {} for incoming events", candidate.id);
 
     let devices_lock = DEVICES.read().await;
@@ -135,12 +834,36 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
 
     log::info!("Reader is ready for {}", candidate.id);
 
+    let mut bad_frames = 0;
+
     loop {
-        log::info!("Reading updates...");
+        // Reads block until device sends something, there is no timeout to wait out
+        if !LOW_LATENCY.load(Ordering::Relaxed) {
+            log::info!("Reading updates...");
+        }
 
         let updates = match reader.read(None).await {
             Ok(updates) => updates,
+            Err(MirajazzError::BadData) if bad_frames + 1 >= BAD_FRAME_LIMIT => {
+                log::warn!(
+                    "Device {} sent {} unparseable frames in a row, resetting it to get back in sync",
+                    candidate.id,
+                    BAD_FRAME_LIMIT
+                );
+                stats::count_drop(&candidate.id, "unparseable frames").await;
+
+                if let Some(reset) = RESET_TOKENS.read().await.get(&candidate.id) {
+                    reset.cancel();
+                }
+
+                break;
+            }
             Err(e) => {
+                if matches!(e, MirajazzError::BadData) {
+                    bad_frames += 1;
+                    stats::count_drop(&candidate.id, "unparseable frames").await;
+                }
+
                 if !handle_error(&candidate.id, e).await {
                     break;
                 }
@@ -149,77 +872,663 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
             }
         };
 
-        for update in updates {
-            log::info!("New update: {:#?}", update);
-
-            let id = candidate.id.clone();
+        bad_frames = 0;
 
-            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        // Input that wakes devices up only wakes them, it's not an action
+        if !updates.is_empty() && eco::activity().await {
+            for update in updates {
                 match update {
-                    DeviceStateUpdate::ButtonDown(key) => outbound.key_down(id, key).await.unwrap(),
-                    DeviceStateUpdate::ButtonUp(key) => outbound.key_up(id, key).await.unwrap(),
-                    DeviceStateUpdate::EncoderDown(encoder) => {
-                        outbound.encoder_down(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderUp(encoder) => {
-                        outbound.encoder_up(id, encoder).await.unwrap();
+                    DeviceStateUpdate::ButtonDown(key) => {
+                        eco::swallow_release(&candidate.id, key).await
                     }
-                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
-                        outbound
-                            .encoder_change(id, encoder, val as i16)
-                            .await
-                            .unwrap();
+                    DeviceStateUpdate::ButtonUp(key) => {
+                        eco::swallowed_release(&candidate.id, key).await;
                     }
+                    _ => {}
                 }
             }
+
+            continue;
         }
+
+        // Presses are timed from here, so latency covers everything plugin does with them
+        let read_at = Instant::now();
+
+        send_updates(candidate, updates, read_at, outbound).await;
     }
 
     Ok(())
 }
 
+/// Sends updates read from the device to OpenDeck
+async fn send_updates(
+    candidate: &CandidateDevice,
+    updates: Vec<DeviceStateUpdate>,
+    read_at: Instant,
+    outbound: &impl Outbound,
+) {
+    for update in updates {
+        if !LOW_LATENCY.load(Ordering::Relaxed) {
+            log::info!("New update: {:#?}", update);
+        }
+
+        // Devices mirroring other devices press keys of the device they mirror
+        let id = SETTINGS
+            .read()
+            .await
+            .mirror_primary(&candidate.id)
+            .unwrap_or(&candidate.id)
+            .clone();
+
+        match update {
+            DeviceStateUpdate::ButtonDown(key) => {
+                let Some(position) = candidate.kind.grid_position(key) else {
+                    log::warn!("Ignoring press of absent key {} of {}", key, candidate.id);
+                    stats::count_drop(&candidate.id, "presses of absent keys").await;
+                    continue;
+                };
+
+                outbound.key_down(&id, position).await.unwrap();
+                stats::record_latency(&candidate.id, read_at.elapsed()).await;
+                stats::count_press(&id, position, candidate.kind.key_count()).await;
+
+                pressed::key_down(&candidate.id, key).await;
+            }
+            DeviceStateUpdate::ButtonUp(key) => {
+                // Press of the key only woke devices up, so OpenDeck never saw it
+                if eco::swallowed_release(&candidate.id, key).await {
+                    continue;
+                }
+
+                let Some(position) = candidate.kind.grid_position(key) else {
+                    continue;
+                };
+
+                outbound.key_up(&id, position).await.unwrap();
+
+                if let Some(device) = DEVICES.read().await.get(&candidate.id) {
+                    pressed::key_up(&candidate.id, device, key).await;
+                }
+            }
+            DeviceStateUpdate::EncoderDown(encoder) => {
+                outbound.encoder_down(&id, encoder).await.unwrap();
+            }
+            DeviceStateUpdate::EncoderUp(encoder) => {
+                outbound.encoder_up(&id, encoder).await.unwrap();
+            }
+            DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                outbound
+                    .encoder_change(&id, encoder, val as i16)
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+}
+
+/// Decode buffer is kept between the images up to this size, so a single huge image doesn't hold its memory forever
+const DECODE_BUFFER_LIMIT: usize = 1024 * 1024;
+
+thread_local! {
+    /// Buffer for decoded image data urls, reused between the images
+    static DECODE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns JPEG from the data url as is, without decoding it
+fn read_jpeg(image: &str) -> Option<Vec<u8>> {
+    let url = DataUrl::process(image).ok()?;
+
+    if url.mime_type().subtype != "jpeg" {
+        return None;
+    }
+
+    url.decode_to_vec().ok().map(|(body, _)| body)
+}
+
+/// Parses data url sent by OpenDeck into an image, returns [None] if image should be skipped
+pub fn decode_image(image: &str) -> Result<Option<DynamicImage>, MirajazzError> {
+    // OpenDeck sends image as a data url, so parse it using a library
+    let url = DataUrl::process(image).map_err(|err| {
+        log::error!("Unable to parse image data url: {:?}", err);
+        MirajazzError::BadData
+    })?;
+
+    // Allow only image/jpeg mime for now, check it before decoding anything
+    if url.mime_type().subtype != "jpeg" {
+        log::error!("Incorrect mime type: {}", url.mime_type());
+
+        return Ok(None); // Not a fatal error, enough to just log it
+    }
+
+    // Decode base64 in chunks straight into a buffer that is reused between the images,
+    // instead of allocating a new one for every key
+    DECODE_BUFFER.with_borrow_mut(|body| {
+        body.clear();
+
+        let decoded = url.decode(|bytes| -> Result<(), Infallible> {
+            body.extend_from_slice(bytes);
+            Ok(())
+        });
+
+        let image = match decoded {
+            Ok(_) => load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)
+                .map(Some)
+                .map_err(MirajazzError::from),
+            Err(err) => {
+                log::error!("Unable to decode image data url: {:?}", err);
+                Err(MirajazzError::BadData)
+            }
+        };
+
+        if body.capacity() > DECODE_BUFFER_LIMIT {
+            body.clear();
+            body.shrink_to(DECODE_BUFFER_LIMIT);
+        }
+
+        image
+    })
+}
+
+/// Scales image to cover the whole grid and writes a slice of it to every key
+async fn set_wallpaper(
+    id: &str,
+    device: &Device,
+    kind: &Kind,
+    image: DynamicImage,
+) -> Result<(), MirajazzError> {
+    let (width, height) = get_wallpaper_size(kind);
+
+    let (image, _) = encode::blocking(move || {
+        image.resize_to_fill(width as u32, height as u32, FilterType::Triangle)
+    })
+    .await;
+
+    // Status tile keeps its key, the rest of the wallpaper is still cut as if the key was there
+    let status = status::tile_position(id)
+        .await
+        .map(|position| kind.matrix_position(position));
+
+    for key in kind.matrix_keys().filter(|key| Some(*key) != status) {
+        let (x, y, width, height) = get_wallpaper_tile(kind, key);
+        let tile = image.crop_imm(x as u32, y as u32, width as u32, height as u32);
+
+        set_button_image(
+            id,
+            device,
+            opendeck_to_device(key),
+            get_image_format_for_key(kind, key),
+            tile,
+        )
+        .await?;
+    }
+
+    flush(device).await?;
+
+    Ok(())
+}
+
+/// Clears the key at the position in the matrix
+async fn clear_key(
+    id: &str,
+    device: &impl Screen,
+    kind: &Kind,
+    position: u8,
+) -> Result<(), MirajazzError> {
+    let key = opendeck_to_device(position);
+
+    IMAGE_CACHE.lock().await.remove(id, key);
+
+    // Clearing a single key is not committed by flush on v2+ devices, so write a blank frame instead
+    let blank = pipeline::blank(&get_image_format_for_key(kind, position))?;
+
+    device.write_image(key, &blank).await?;
+    flush(device).await
+}
+
 /// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
 pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
-    match (evt.position, evt.image) {
+    let id = evt.device.clone();
+    let kind = device_kind(&id, device).await;
+
+    // Status tile takes the key over, so images OpenDeck sends for it are not shown
+    if evt.position.is_some() && evt.position == status::tile_position(&id).await {
+        log::debug!("Dropping image for the status tile of {}", id);
+        return Ok(());
+    }
+
+    let key = evt
+        .position
+        .map(|position| opendeck_to_device(kind.matrix_position(position)));
+    fade::image_replaced(&id, key).await;
+
+    wait_for_brightness(&id).await;
+
+    let started = Instant::now();
+    let transfer = lock_transfers(&id, &kind).await;
+    let result = set_image(device, evt).await;
+    drop(transfer);
+
+    trace::stage(if result.is_ok() { "written" } else { "failed" });
+
+    backlog::record_write(&id, started.elapsed()).await;
+
+    track_writes(&id, &result).await;
+
+    result
+}
+
+/// Returns how many images in a row device failed to accept
+pub async fn write_failures(id: &str) -> usize {
+    WRITE_FAILURES.lock().await.get(id).copied().unwrap_or(0)
+}
+
+/// Counts failed writes, reporting device that keeps failing them, because it would just stay blank otherwise
+async fn track_writes(id: &str, result: &Result<(), MirajazzError>) {
+    let mut failures = WRITE_FAILURES.lock().await;
+
+    // Only failures to talk to the device count, broken images are not device's fault
+    let Err(MirajazzError::HidError(err)) = result else {
+        failures.remove(id);
+        return;
+    };
+
+    let count = failures.entry(id.to_string()).or_default();
+    *count += 1;
+
+    if *count != WRITE_FAILURE_LIMIT {
+        return;
+    }
+
+    let message = format!(
+        "Device {} is connected, but failed to accept {} images in a row, try replugging it: {}",
+        id, WRITE_FAILURE_LIMIT, err
+    );
+
+    log::error!("{}", message);
+
+    outbound::notify_later(message).await;
+}
+
+async fn set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
+    let kind = device_kind(&evt.device, device).await;
+
+    // OpenDeck grid skips absent keys, so positions have to be translated into the matrix
+    let position = evt.position.map(|position| kind.matrix_position(position));
+
+    pressed::image_replaced(&evt.device, position.map(opendeck_to_device)).await;
+
+    match (position, evt.image) {
         (Some(position), Some(image)) => {
-            log::info!("Setting image for button {}", position);
+            log::info!(
+                "Setting image for button {} (trace {})",
+                position,
+                trace::id()
+            );
+
+            let key = opendeck_to_device(position);
+            let format = get_image_format_for_key(&kind, position);
 
-            // OpenDeck sends image as a data url, so parse it using a library
-            let url = DataUrl::process(image.as_str()).unwrap(); // Isn't expected to fail, so unwrap it is
-            let (body, _fragment) = url.decode_to_vec().unwrap(); // Same here
+            let jpeg = can_pass_through(&evt.device, device, key, &format)
+                .await
+                .then(|| read_jpeg(&image))
+                .flatten()
+                .filter(|data| pipeline::jpeg_size(data) == Some(format.size));
+
+            let data = match jpeg {
+                Some(data) => {
+                    log::debug!(
+                        "Image is a JPEG of the key size already, writing it as is (trace {})",
+                        trace::id()
+                    );
+                    trace::stage("passed through");
+
+                    data
+                }
+                None => match encode_source(&evt.device, device, key, format, &image).await? {
+                    Some(data) => data,
+                    None => return Ok(()),
+                },
+            };
 
-            // Allow only image/jpeg mime for now
-            if url.mime_type().subtype != "jpeg" {
-                log::error!("Incorrect mime type: {}", url.mime_type());
+            let previous = match fade::should_fade(&evt.device, &kind).await {
+                true => IMAGE_CACHE
+                    .lock()
+                    .await
+                    .get(&evt.device, key)
+                    .filter(|previous| *previous != data),
+                false => None,
+            };
 
-                return Ok(()); // Not a fatal error, enough to just log it
+            // Fades run in the background, so the new image is only cached here
+            if let Some(previous) = previous {
+                cache_button_data(&evt.device, key, data.clone()).await;
+                trace::stage("fading");
+                fade::start(evt.device.clone(), key, format, previous, data).await;
+
+                return Ok(());
             }
 
-            let image = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+            set_button_data(&evt.device, device, key, data).await?;
+            flush(device).await?;
+        }
+        (None, Some(image)) => {
+            log::info!("Setting wallpaper (trace {})", trace::id());
+
+            let (image, _) = encode::blocking(move || decode_image(&image)).await;
 
-            let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap(); // Safe to unwrap here, because device is already filtered
+            let Some(image) = image? else {
+                stats::count_drop(&evt.device, "unsupported wallpapers").await;
+                return Ok(());
+            };
 
-            device
-                .set_button_image(
-                    opendeck_to_device(position),
-                    get_image_format_for_key(&kind, position),
-                    image,
-                )
-                .await?;
-            device.flush().await?;
+            set_wallpaper(&evt.device, device, &kind, image).await?;
         }
         (Some(position), None) => {
-            device
-                .clear_button_image(opendeck_to_device(position))
-                .await?;
-            device.flush().await?;
+            log::info!(
+                "Clearing image for button {} (trace {})",
+                position,
+                trace::id()
+            );
+
+            clear_key(&evt.device, device, &kind, position).await?;
         }
         (None, None) => {
+            IMAGE_CACHE.lock().await.remove_device(&evt.device);
+
             device.clear_all_button_images().await?;
-            device.flush().await?;
+            flush(device).await?;
         }
-        _ => {}
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{outbound::tests::Recorder, screen};
+    use async_hid::DeviceId;
+    use mirajazz::types::HidDeviceInfo;
+
+    fn base64(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        data.chunks(3)
+            .flat_map(|chunk| {
+                let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+                    bits | (*byte as u32) << (16 - 8 * i)
+                });
+
+                (0..4).map(move |i| {
+                    if i <= chunk.len() {
+                        ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char
+                    } else {
+                        '='
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn data_url(jpeg: &[u8]) -> String {
+        format!("data:image/jpeg;base64,{}", base64(jpeg))
+    }
+
+    /// Encodes noise, which compresses badly, like detailed icons do
+    fn noise_jpeg(size: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(size, size, |x, y| {
+            let value = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)).wrapping_mul(2654435761);
+            image::Rgb([(value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+        });
+        let format = get_image_format_for_key(&Kind::AKP153, 0);
+
+        pipeline::encode(
+            &format,
+            &pipeline::JpegOptions::default(),
+            DynamicImage::ImageRgb8(image),
+        )
+        .unwrap()
+    }
+
+    /// Returns address and capacity of the decode buffer of the current thread
+    fn decode_buffer() -> (*const u8, usize) {
+        DECODE_BUFFER.with_borrow(|body| (body.as_ptr(), body.capacity()))
+    }
+
+    #[test]
+    fn decode_buffer_is_reused_between_images() {
+        let jpeg = noise_jpeg(85);
+        let url = data_url(&jpeg);
+
+        let image = decode_image(&url).unwrap().unwrap();
+        assert_eq!((image.width(), image.height()), (85, 85));
+
+        let (buffer, capacity) = decode_buffer();
+        assert!(capacity >= jpeg.len());
+
+        // Decoded data of the next images goes into the buffer of the first one
+        for _ in 1..18 {
+            decode_image(&url).unwrap();
+
+            assert_eq!(decode_buffer(), (buffer, capacity));
+        }
+    }
+
+    #[test]
+    fn huge_image_does_not_keep_decode_buffer() {
+        let url = data_url(&vec![0; 2 * DECODE_BUFFER_LIMIT]);
+
+        assert!(decode_image(&url).is_err());
+        assert!(DECODE_BUFFER.with_borrow(|body| body.capacity()) <= DECODE_BUFFER_LIMIT);
+    }
+
+    #[test]
+    fn malformed_data_url_is_bad_data() {
+        for url in ["not a data url", "data:image/jpeg;base64,@@@@"] {
+            assert!(
+                matches!(decode_image(url), Err(MirajazzError::BadData)),
+                "{}",
+                url
+            );
+        }
+
+        assert!(matches!(decode_image("data:image/png;base64,"), Ok(None)));
+    }
+
+    #[test]
+    fn encode_buffer_is_allocated_once() {
+        let format = get_image_format_for_key(&Kind::AKP153, 0);
+        let image = || DynamicImage::ImageRgb8(image::RgbImage::new(85, 85));
+        let encode = || pipeline::encode(&format, &pipeline::JpegOptions::default(), image());
+
+        // Buffer of the first image grows step by step, the next ones get the size of the previous one upfront
+        let first = encode().unwrap();
+        let next = encode().unwrap();
+
+        assert_eq!(next, first);
+        assert_eq!(next.capacity(), first.len());
+    }
+
+    /// Encodes an image for every key of the kind, keyed like the image cache
+    fn cached_images(kind: &Kind) -> KeyImages {
+        (0..kind.key_count() as u8)
+            .map(|position| {
+                let format = get_image_format_for_key(kind, position);
+                let (width, height) = format.size;
+                let image = DynamicImage::new_rgb8(width as u32, height as u32);
+                let data =
+                    pipeline::process(&format, &pipeline::Options::default(), image).unwrap();
+
+                (opendeck_to_device(position), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn images_of_same_kind_are_restored() {
+        let (images, stale) = split_stale(&Kind::HSV293SV3, cached_images(&Kind::HSV293SV3));
+
+        assert_eq!(images.len(), Kind::HSV293SV3.key_count());
+        assert!(stale.is_empty());
+    }
+
+    #[test]
+    fn images_of_v1_kind_are_not_restored_to_v2_kind_with_same_id() {
+        let (images, stale) = split_stale(&Kind::HSV293SV3, cached_images(&Kind::AKP153));
+
+        assert!(images.is_empty());
+        assert_eq!(stale.len(), Kind::AKP153.key_count());
+    }
+
+    #[tokio::test]
+    async fn cleared_key_gets_blank_frame_once() {
+        let screen = screen::tests::Recorder::default();
+        let kind = Kind::HSV293SV3;
+        let key = opendeck_to_device(4);
+        let blank = pipeline::blank(&get_image_format_for_key(&kind, 4)).unwrap();
+
+        IMAGE_CACHE.lock().await.insert("159-A", key, vec![1, 2, 3]);
+
+        clear_key("159-A", &screen, &kind, 4).await.unwrap();
+
+        assert_eq!(
+            screen.writes(),
+            [
+                format!("write_image {} {}", key, blank.len()),
+                "flush".to_string()
+            ]
+        );
+        assert!(IMAGE_CACHE.lock().await.device_images("159-A").0.is_empty());
+    }
+
+    fn candidate(id: &str, kind: Kind) -> CandidateDevice {
+        CandidateDevice {
+            id: id.to_string(),
+            dev: HidDeviceInfo {
+                id: DeviceId::DevPath("/dev/hidraw3".into()),
+                name: "355 35549".to_string(),
+                product_id: 0,
+                vendor_id: 0,
+                usage_id: 1,
+                usage_page: 65440,
+                serial_number: None,
+            },
+            kind,
+        }
+    }
+
+    #[tokio::test]
+    async fn updates_are_sent_to_opendeck() {
+        let recorder = Recorder::default();
+        let updates = vec![
+            DeviceStateUpdate::ButtonDown(3),
+            DeviceStateUpdate::ButtonUp(3),
+            DeviceStateUpdate::EncoderDown(1),
+            DeviceStateUpdate::EncoderTwist(1, -2),
+            DeviceStateUpdate::EncoderUp(1),
+        ];
+
+        send_updates(
+            &candidate("165-A", Kind::AKP153),
+            updates,
+            Instant::now(),
+            &recorder,
+        )
+        .await;
+
+        assert_eq!(
+            recorder.events(),
+            [
+                "key_down 165-A 3",
+                "key_up 165-A 3",
+                "encoder_down 165-A 1",
+                "encoder_change 165-A 1 -2",
+                "encoder_up 165-A 1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn presses_of_keys_beyond_matrix_are_not_sent() {
+        let recorder = Recorder::default();
+        let key = KEY_COUNT as u8;
+
+        send_updates(
+            &candidate("165-B", Kind::AKP153),
+            vec![
+                DeviceStateUpdate::ButtonDown(key),
+                DeviceStateUpdate::ButtonUp(key),
+            ],
+            Instant::now(),
+            &recorder,
+        )
+        .await;
+
+        assert!(recorder.events().is_empty());
+    }
+
+    /// Starts image transfer and brightness command at the same time, returning the order they went in
+    async fn transfer_and_command(id: &str, kind: Kind) -> Vec<&'static str> {
+        let order = std::sync::Mutex::new(Vec::new());
+
+        let transfer = async {
+            let _transfer = lock_transfers(id, &kind).await;
+            order.lock().unwrap().push("transfer started");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            order.lock().unwrap().push("transfer finished");
+        };
+
+        let command = async {
+            // Transfer starts first
+            tokio::task::yield_now().await;
+
+            let _transfer = lock_transfers(id, &kind).await;
+            order.lock().unwrap().push("command");
+        };
+
+        tokio::join!(transfer, command);
+
+        order.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands_wait_for_transfers_on_msd_one() {
+        assert_eq!(
+            transfer_and_command("172-A", Kind::MSDONE).await,
+            ["transfer started", "transfer finished", "command"]
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_interleave_transfers_on_other_kinds() {
+        assert_eq!(
+            transfer_and_command("172-B", Kind::AKP153).await,
+            ["transfer started", "command", "transfer finished"]
+        );
+    }
+
+    #[tokio::test]
+    async fn images_wait_for_brightness_on_akp153e_rev2() {
+        let kind = Kind::AKP153EREV2;
+
+        hold_images("195-A", &kind).await;
+
+        let started = Instant::now();
+        wait_for_brightness("195-A").await;
+        assert!(started.elapsed() >= kind.brightness_settle_time());
+
+        // Only the first image after the change waits
+        let started = Instant::now();
+        wait_for_brightness("195-A").await;
+        assert!(started.elapsed() < kind.brightness_settle_time());
+    }
+
+    #[tokio::test]
+    async fn images_dont_wait_for_brightness_on_other_kinds() {
+        hold_images("195-B", &Kind::AKP153E).await;
+
+        let started = Instant::now();
+        wait_for_brightness("195-B").await;
+        assert!(started.elapsed() < Kind::AKP153EREV2.brightness_settle_time());
+    }
+}
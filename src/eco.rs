@@ -0,0 +1,102 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::{DEVICES, SETTINGS, TRACKER, device};
+
+/// How often inactivity is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether devices were put to sleep because nothing was pressed for a while
+static ASLEEP: AtomicBool = AtomicBool::new(false);
+
+/// When anything was last pressed or turned on any device
+static LAST_ACTIVITY: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+/// Keys by device id and device key that woke devices up, their releases are not sent to OpenDeck either
+static WAKE_PRESSES: LazyLock<Mutex<HashSet<(String, u8)>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Puts all the devices to sleep once nothing was pressed for as long as settings allow
+///
+/// OpenDeck only tells plugins when the system wakes up, not when it goes idle,
+/// so inactivity of the devices is the signal to sleep
+pub async fn eco_task(token: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {},
+            _ = token.cancelled() => break
+        }
+
+        let Some(timeout) = SETTINGS.read().await.eco_mode_timeout else {
+            continue;
+        };
+
+        if asleep() || LAST_ACTIVITY.lock().await.elapsed() < Duration::from_secs(timeout) {
+            continue;
+        }
+
+        sleep_all().await;
+    }
+}
+
+async fn sleep_all() {
+    log::info!("Nothing was pressed for a while, putting devices to sleep");
+
+    ASLEEP.store(true, Ordering::Relaxed);
+
+    for (id, device) in DEVICES.read().await.iter() {
+        if let Err(err) = device.sleep().await {
+            log::warn!("Unable to put device {} to sleep: {}", id, err);
+        }
+    }
+}
+
+/// Checks if devices were put to sleep, so nothing is drawn on them until they wake up
+pub fn asleep() -> bool {
+    ASLEEP.load(Ordering::Relaxed)
+}
+
+/// Records input from the device or the system waking up, waking all the devices up if they were asleep
+///
+/// Returns true if the input woke them, so it's not sent to OpenDeck as an action
+pub async fn activity() -> bool {
+    *LAST_ACTIVITY.lock().await = Instant::now();
+
+    if !ASLEEP.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+
+    // Devices stay connected while asleep, so waking them is not counted as a reconnect.
+    // Spawned so the input that woke them is not held up by writing images back
+    TRACKER.lock().await.spawn(wake_all());
+
+    true
+}
+
+async fn wake_all() {
+    log::info!("Waking devices up");
+
+    for (id, device) in DEVICES.read().await.iter() {
+        if let Err(err) = device::wake(id, device).await {
+            log::warn!("Unable to wake device {} up: {}", id, err);
+        }
+    }
+}
+
+/// Remembers key that woke devices up, so its release is swallowed too
+pub async fn swallow_release(id: &str, key: u8) {
+    WAKE_PRESSES.lock().await.insert((id.to_string(), key));
+}
+
+/// Checks if the key release belongs to the press that woke devices up
+pub async fn swallowed_release(id: &str, key: u8) -> bool {
+    WAKE_PRESSES.lock().await.remove(&(id.to_string(), key))
+}
@@ -0,0 +1,528 @@
+use image::DynamicImage;
+use mirajazz::{
+    device::Device,
+    error::MirajazzError,
+    types::{ImageFormat, ImageMirroring, ImageMode, ImageRotation},
+};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::{
+    IMAGE_CACHE, SETTINGS, backlog,
+    cache::LruCache,
+    device::{decode_image, device_kind},
+    memory,
+    pipeline::{self, DEFAULT_JPEG_QUALITY, JpegOptions, Options},
+    stats, trace,
+};
+
+/// Default limit for each of the transform caches, in bytes
+pub const DEFAULT_TRANSFORM_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Decoded images by hash of their data url
+static SOURCES: LazyLock<Mutex<LruCache<u64, Arc<DynamicImage>>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(DEFAULT_TRANSFORM_CACHE_SIZE)));
+
+/// Hashes of data url, format and pipeline options of the encoded image
+type OutputKey = (u64, u64, u64);
+
+/// Encoded images by hashes of their data url, format and pipeline options
+static OUTPUTS: LazyLock<Mutex<LruCache<OutputKey, Vec<u8>>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(DEFAULT_TRANSFORM_CACHE_SIZE)));
+
+/// Bounds number of images being decoded, resized or encoded at the same time, shared between all the devices
+static PERMITS: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(default_limit()));
+static LIMIT: LazyLock<AtomicUsize> = LazyLock::new(|| AtomicUsize::new(default_limit()));
+
+/// Default limit of concurrent encodes, which is the number of cores
+pub fn default_limit() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Returns limit of concurrent encodes
+pub fn limit() -> usize {
+    LIMIT.load(Ordering::Acquire)
+}
+
+/// Changes limit of concurrent encodes, already running encodes are not affected
+pub fn set_limit(limit: usize) {
+    let limit = limit.max(1);
+    let previous = LIMIT.swap(limit, Ordering::AcqRel);
+
+    if limit > previous {
+        PERMITS.add_permits(limit - previous);
+    } else if limit < previous {
+        // Some permits could be taken by running encodes, so wait for them to be released before forgetting
+        let extra = (previous - limit) as u32;
+
+        tokio::spawn(async move {
+            PERMITS.acquire_many(extra).await.unwrap().forget();
+        });
+    }
+
+    log::info!("Limiting concurrent image encodes to {}", limit);
+}
+
+/// Builds pipeline options for the device from its kind and settings
+async fn get_options(id: &str, device: &Device, key: u8) -> Options {
+    let kind = device_kind(id, device).await;
+    let reduction = backlog::quality_reduction(id).await;
+    let settings = SETTINGS.read().await;
+
+    // Curves from settings take priority over the ones kind ships with
+    let curves = match settings.color_curves.get(id) {
+        Some(curves) if curves.is_valid() => Some(curves.clone()),
+        Some(_) => {
+            log::error!("Color curves for {} must have 256 values per channel", id);
+            kind.color_curves()
+        }
+        None => kind.color_curves(),
+    };
+
+    let jpeg = JpegOptions {
+        quality: settings
+            .jpeg_quality
+            .get(id)
+            .map(|quality| (*quality).clamp(1, 100))
+            .unwrap_or(DEFAULT_JPEG_QUALITY)
+            .saturating_sub(reduction)
+            .max(1),
+    };
+
+    Options {
+        zoom: settings.zoom(id, key),
+        anchor: settings
+            .crop_anchors
+            .get(id)
+            .and_then(|anchors| anchors.get(&key))
+            .copied(),
+        invert: settings.invert_images.contains(id),
+        boost_contrast: settings.boost_contrast.contains(id),
+        curves,
+        dim: settings.dim_level(id, key),
+        rotation: settings.rotation_override(id),
+        jpeg,
+    }
+}
+
+/// Runs work on an image on the blocking pool, so runtime workers stay free to forward key presses
+///
+/// Waits for a free slot first if too many images are processed already, returns how long it waited
+pub async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> T + Send + 'static,
+) -> (T, Duration) {
+    let started = Instant::now();
+    let permit = PERMITS.acquire().await.unwrap(); // Semaphore is never closed
+    let waited = started.elapsed();
+
+    // Work can only fail to join by panicking, so propagate the panic
+    let result = tokio::task::spawn_blocking(work).await.unwrap();
+
+    drop(permit);
+
+    (result, waited)
+}
+
+/// Checks if JPEG of the exact key size can be written to the key as is, skipping decoding and encoding
+pub async fn can_pass_through(id: &str, device: &Device, key: u8, format: &ImageFormat) -> bool {
+    let options = get_options(id, device, key).await;
+    let pre_transformed = SETTINGS.read().await.pre_transformed_images.contains(id);
+
+    passes_through(&options, format, pre_transformed)
+}
+
+/// Checks if the options leave images for the key in the format device expects them in
+fn passes_through(options: &Options, format: &ImageFormat, pre_transformed: bool) -> bool {
+    // Images prepared for the device are already rotated and mirrored the way it expects
+    let transformed = matches!(
+        options.rotation.unwrap_or(format.rotation),
+        ImageRotation::Rot0
+    ) && matches!(format.mirror, ImageMirroring::None)
+        || pre_transformed;
+
+    matches!(format.mode, ImageMode::JPEG) && options.keeps_pixels() && transformed
+}
+
+/// Queues already encoded image for the key
+pub async fn set_button_data(
+    id: &str,
+    device: &Device,
+    key: u8,
+    data: Vec<u8>,
+) -> Result<(), MirajazzError> {
+    // Cached before it's written, so a fade that finishes at the same time puts this image back
+    cache_button_data(id, key, data.clone()).await;
+
+    device.write_image(key, &data).await
+}
+
+/// Remembers image of the key as the one it shows, for images written to the device some other way
+pub async fn cache_button_data(id: &str, key: u8, data: Vec<u8>) {
+    IMAGE_CACHE.lock().await.insert(id, key, data);
+    stats::count_image(id).await;
+
+    memory::enforce().await;
+}
+
+/// Encodes image on the blocking pool, waiting for a free slot if too many images are encoded already
+async fn encode(
+    format: ImageFormat,
+    options: Options,
+    image: DynamicImage,
+) -> Result<Vec<u8>, MirajazzError> {
+    let started = Instant::now();
+    let (data, waited) = blocking(move || pipeline::process(&format, &options, image)).await;
+    let data = data?;
+
+    log::debug!(
+        "Encoded image in {:?}, after waiting {:?} for a slot (trace {})",
+        started.elapsed() - waited,
+        waited,
+        trace::id()
+    );
+    trace::stage("encoded");
+
+    Ok(data)
+}
+
+/// Encodes and queues the image for the key
+pub async fn set_button_image(
+    id: &str,
+    device: &Device,
+    key: u8,
+    format: ImageFormat,
+    image: DynamicImage,
+) -> Result<(), MirajazzError> {
+    let options = get_options(id, device, key).await;
+    let data = encode(format, options, image).await?;
+
+    set_button_data(id, device, key, data).await
+}
+
+/// Decodes and encodes image from the data url for the key, reusing work done for the same image before,
+/// returning [None] if image is not supported
+///
+/// Same icon is often set to several keys, and to keys of different sizes, so decoded images
+/// and encoded images are cached separately
+pub async fn encode_source(
+    id: &str,
+    device: &Device,
+    key: u8,
+    format: ImageFormat,
+    source: &str,
+) -> Result<Option<Vec<u8>>, MirajazzError> {
+    let options = get_options(id, device, key).await;
+
+    let source_hash = hash(source);
+    let output_key = (source_hash, hash(&format), hash(&options));
+
+    if let Some(data) = OUTPUTS.lock().await.get(&output_key) {
+        log::debug!(
+            "Reusing encoded image for key {} of {} (trace {})",
+            key,
+            id,
+            trace::id()
+        );
+        trace::stage("cache hit");
+
+        return Ok(Some(data));
+    }
+
+    let cached = SOURCES.lock().await.get(&source_hash);
+
+    let image = match cached {
+        Some(image) => image,
+        None => {
+            let source = source.to_string();
+            let (image, _) = blocking(move || decode_image(&source)).await;
+
+            let Some(image) = image? else {
+                stats::count_drop(id, "unsupported images").await;
+                return Ok(None);
+            };
+
+            trace::stage("decoded");
+
+            let image = Arc::new(image);
+            let size = image.as_bytes().len();
+
+            SOURCES
+                .lock()
+                .await
+                .insert(source_hash, image.clone(), size);
+
+            image
+        }
+    };
+
+    let data = encode(format, options, (*image).clone()).await?;
+
+    OUTPUTS
+        .lock()
+        .await
+        .insert(output_key, data.clone(), data.len());
+
+    Ok(Some(data))
+}
+
+/// Blends already encoded images of the key into intermediate frames, for images fading into each other
+///
+/// Images are already rotated and mirrored for the device, so they are only blended and encoded again
+pub async fn blend_frames(
+    id: &str,
+    device: &Device,
+    key: u8,
+    format: ImageFormat,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    count: usize,
+) -> Result<Vec<Vec<u8>>, MirajazzError> {
+    let jpeg = get_options(id, device, key).await.jpeg;
+    let permit = PERMITS.acquire().await.unwrap(); // Semaphore is never closed
+
+    let frames = tokio::task::spawn_blocking(move || {
+        let from = image::load_from_memory(&from)?;
+        let to = image::load_from_memory(&to)?;
+
+        (1..=count)
+            .map(|frame| {
+                let amount = frame as f32 / (count + 1) as f32;
+
+                pipeline::encode(&format, &jpeg, pipeline::blend(&from, &to, amount))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .unwrap()?; // Encode can only fail to join by panicking, so propagate the panic
+
+    drop(permit);
+
+    Ok(frames)
+}
+
+/// Darkens already encoded image of the key, for keys that are held down
+///
+/// Image is already rotated and mirrored for the device, so only colors are changed.
+/// Darkened images are cached with the encoded ones, by hash of the image they are made from
+pub async fn pressed_variant(
+    format: ImageFormat,
+    data: Vec<u8>,
+    dim: u8,
+) -> Result<Vec<u8>, MirajazzError> {
+    let output_key = (hash(&data), hash("pressed"), hash(&dim));
+
+    if let Some(variant) = OUTPUTS.lock().await.get(&output_key) {
+        return Ok(variant);
+    }
+
+    let permit = PERMITS.acquire().await.unwrap(); // Semaphore is never closed
+
+    let variant = tokio::task::spawn_blocking(move || {
+        let options = Options {
+            dim: Some(dim),
+            ..Default::default()
+        };
+
+        let image = image::load_from_memory(&data)?;
+
+        pipeline::encode(
+            &format,
+            &options.jpeg,
+            pipeline::correct_colors(&options, image),
+        )
+    })
+    .await
+    .unwrap()?; // Encode can only fail to join by panicking, so propagate the panic
+
+    drop(permit);
+
+    OUTPUTS
+        .lock()
+        .await
+        .insert(output_key, variant.clone(), variant.len());
+
+    Ok(variant)
+}
+
+/// Changes limit of each of the transform caches
+pub async fn set_transform_cache_limit(limit: usize) {
+    SOURCES.lock().await.set_limit(limit);
+    OUTPUTS.lock().await.set_limit(limit);
+}
+
+/// Returns sizes of the decoded and encoded image caches, in bytes
+pub async fn transform_usage() -> (usize, usize) {
+    let sources = SOURCES.lock().await.usage();
+    let outputs = OUTPUTS.lock().await.usage();
+
+    (sources, outputs)
+}
+
+/// Evicts encoded images until they take at most `size` bytes, returning their size
+pub async fn shrink_outputs(size: usize) -> usize {
+    let mut outputs = OUTPUTS.lock().await;
+    outputs.shrink(size);
+
+    outputs.usage()
+}
+
+/// Evicts decoded images until they take at most `size` bytes, returning their size
+pub async fn shrink_sources(size: usize) -> usize {
+    let mut sources = SOURCES.lock().await;
+    sources.shrink(size);
+
+    sources.usage()
+}
+
+/// Logs hits and misses of the transform caches
+pub async fn log_transform_stats() {
+    let sources = SOURCES.lock().await;
+    let (hits, misses) = sources.stats();
+
+    log::debug!(
+        "Decoded image cache: {} hits, {} misses, {} bytes used",
+        hits,
+        misses,
+        sources.usage()
+    );
+
+    drop(sources);
+
+    let outputs = OUTPUTS.lock().await;
+    let (hits, misses) = outputs.stats();
+
+    log::debug!(
+        "Encoded image cache: {} hits, {} misses, {} bytes used",
+        hits,
+        misses,
+        outputs.usage()
+    );
+}
+
+fn hash(value: &(impl Hash + ?Sized)) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{RgbImage, codecs::jpeg::JpegEncoder};
+
+    const FORMAT: ImageFormat = ImageFormat {
+        mode: ImageMode::JPEG,
+        size: (85, 85),
+        rotation: ImageRotation::Rot0,
+        mirror: ImageMirroring::None,
+    };
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        JpegEncoder::new(&mut data)
+            .encode_image(&RgbImage::new(width, height))
+            .unwrap();
+
+        data
+    }
+
+    /// Checks the JPEG the same way images from OpenDeck are checked before they are written as is
+    fn written_as_is(options: &Options, format: &ImageFormat, data: &[u8]) -> bool {
+        passes_through(options, format, false) && pipeline::jpeg_size(data) == Some(format.size)
+    }
+
+    #[test]
+    fn jpeg_of_key_size_is_written_as_is() {
+        assert!(written_as_is(&Options::default(), &FORMAT, &jpeg(85, 85)));
+    }
+
+    #[test]
+    fn jpeg_of_another_size_is_encoded() {
+        assert_eq!(pipeline::jpeg_size(&jpeg(72, 72)), Some((72, 72)));
+        assert!(!written_as_is(&Options::default(), &FORMAT, &jpeg(72, 72)));
+    }
+
+    #[test]
+    fn progressive_jpeg_is_encoded() {
+        let mut data = jpeg(85, 85);
+
+        // Turn baseline frame header into a progressive one
+        let header = data
+            .windows(2)
+            .position(|marker| marker == [0xFF, 0xC0])
+            .unwrap();
+        data[header + 1] = 0xC2;
+
+        assert_eq!(pipeline::jpeg_size(&data), None);
+        assert!(!written_as_is(&Options::default(), &FORMAT, &data));
+    }
+
+    #[test]
+    fn jpeg_is_encoded_when_options_change_pixels() {
+        let options = Options {
+            invert: true,
+            ..Default::default()
+        };
+
+        assert!(!written_as_is(&options, &FORMAT, &jpeg(85, 85)));
+    }
+
+    #[test]
+    fn jpeg_for_rotated_key_is_written_as_is_only_when_pre_transformed() {
+        let format = ImageFormat {
+            rotation: ImageRotation::Rot180,
+            ..FORMAT
+        };
+
+        assert!(!passes_through(&Options::default(), &format, false));
+        assert!(passes_through(&Options::default(), &format, true));
+    }
+
+    #[test]
+    fn options_changing_the_image_change_the_hash() {
+        let base = Options::default();
+        let changed = [
+            Options {
+                zoom: Some(1.5),
+                ..Default::default()
+            },
+            Options {
+                anchor: Some(Default::default()),
+                ..Default::default()
+            },
+            Options {
+                invert: true,
+                ..Default::default()
+            },
+            Options {
+                dim: Some(50),
+                ..Default::default()
+            },
+            Options {
+                rotation: Some(ImageRotation::Rot90),
+                ..Default::default()
+            },
+            Options {
+                jpeg: JpegOptions { quality: 50 },
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(hash(&base), hash(&Options::default()));
+
+        for options in changed {
+            assert_ne!(hash(&options), hash(&base), "{:?}", options);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use mirajazz::types::ImageFormat;
+use std::{
+    collections::HashMap,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    DEVICES, IMAGE_CACHE, SETTINGS, TRACKER, device::write_key, eco, encode::blend_frames,
+    mappings::Kind, trace,
+};
+
+/// Intermediate frames shown between the old and the new image
+const FADE_FRAMES: usize = 3;
+
+/// How long every frame stays on the key
+const FRAME_TIME: Duration = Duration::from_millis(30);
+
+/// Images for the same device arriving closer than this are a batch, like a profile switch, and don't fade
+const BATCH_WINDOW: Duration = Duration::from_millis(100);
+
+/// Generation of the last image set to the key by device id and device key, fades of older generations are abandoned
+static GENERATIONS: LazyLock<Mutex<HashMap<(String, u8), u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// When the last image for the device arrived, and whether it was a part of a batch, by device id
+static LAST_IMAGES: LazyLock<Mutex<HashMap<String, (Instant, bool)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Abandons fade of the key, or of all the keys if there is no key, because OpenDeck sent a newer image
+pub async fn image_replaced(id: &str, key: Option<u8>) {
+    let now = Instant::now();
+    let mut last_images = LAST_IMAGES.lock().await;
+    let in_batch = last_images
+        .get(id)
+        .is_some_and(|(last, _)| now - *last < BATCH_WINDOW);
+
+    last_images.insert(id.to_string(), (now, in_batch));
+    drop(last_images);
+
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    let mut generations = GENERATIONS.lock().await;
+
+    match key {
+        Some(key) => {
+            generations.insert((id.to_string(), key), generation);
+        }
+        None => {
+            for ((generation_id, _), current) in generations.iter_mut() {
+                if generation_id == id {
+                    *current = generation;
+                }
+            }
+        }
+    }
+}
+
+/// Checks if image that just arrived should fade in, which only happens on v2+ devices with fading enabled,
+/// and not during batches
+pub async fn should_fade(id: &str, kind: &Kind) -> bool {
+    // Fades of sleeping devices would be written to screens that are off
+    if kind.protocol_version() < 2
+        || eco::asleep()
+        || !SETTINGS.read().await.fade_images.contains(id)
+    {
+        return false;
+    }
+
+    LAST_IMAGES
+        .lock()
+        .await
+        .get(id)
+        .is_some_and(|(_, in_batch)| !in_batch)
+}
+
+/// Checks if more images for the device arrived since the fade started
+async fn batch_started(id: &str, since: Instant) -> bool {
+    LAST_IMAGES
+        .lock()
+        .await
+        .get(id)
+        .is_some_and(|(last, _)| *last > since)
+}
+
+/// Fades key from the image it shows to the new one in the background, new image has to be cached already
+pub async fn start(id: String, key: u8, format: ImageFormat, from: Vec<u8>, to: Vec<u8>) {
+    let generation = GENERATIONS.lock().await.get(&(id.clone(), key)).copied();
+    let trace = trace::id();
+
+    TRACKER.lock().await.spawn(async move {
+        fade(&id, key, format, from, to, generation, trace).await;
+    });
+}
+
+async fn fade(
+    id: &str,
+    key: u8,
+    format: ImageFormat,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    generation: Option<u64>,
+    trace: u32,
+) {
+    let started = Instant::now();
+
+    let frames = match DEVICES.read().await.get(id) {
+        Some(device) => blend_frames(id, device, key, format, from, to.clone(), FADE_FRAMES).await,
+        None => return,
+    };
+
+    let frames = frames.unwrap_or_else(|err| {
+        log::warn!("Unable to blend images of key {} of {}: {}", key, id, err);
+        Vec::new()
+    });
+
+    for frame in frames {
+        // Rest of the batch is written right away, so the fade only finishes the key off
+        if batch_started(id, started).await {
+            break;
+        }
+
+        if !write_frame(id, key, &frame, generation, trace).await {
+            return;
+        }
+
+        tokio::time::sleep(FRAME_TIME).await;
+    }
+
+    write_frame(id, key, &to, generation, trace).await;
+}
+
+/// Checks if no newer image was set to the key since the fade started
+async fn is_current(id: &str, key: u8, generation: Option<u64>) -> bool {
+    GENERATIONS
+        .lock()
+        .await
+        .get(&(id.to_string(), key))
+        .copied()
+        == generation
+}
+
+/// Writes frame of the fade, returning false if the fade has to be abandoned
+async fn write_frame(id: &str, key: u8, frame: &[u8], generation: Option<u64>, trace: u32) -> bool {
+    if !is_current(id, key, generation).await {
+        log::debug!(
+            "Abandoning fade of key {} of {}, newer image arrived (trace {})",
+            key,
+            id,
+            trace
+        );
+        return false;
+    }
+
+    let devices = DEVICES.read().await;
+    let Some(device) = devices.get(id) else {
+        return false;
+    };
+
+    if let Err(err) = write_key(id, device, key, frame).await {
+        log::warn!(
+            "Unable to write fade frame to key {} of {}: {}",
+            key,
+            id,
+            err
+        );
+        return false;
+    }
+
+    // Newer image could be written between the check and the frame, so put it back over the frame.
+    // Images are cached before they are written, so the cache has it already
+    if !is_current(id, key, generation).await {
+        let current = IMAGE_CACHE.lock().await.get(id, key);
+
+        if let Some(current) = current {
+            write_key(id, device, key, &current).await.ok();
+        }
+
+        return false;
+    }
+
+    true
+}
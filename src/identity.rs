@@ -0,0 +1,94 @@
+use serde::Serialize;
+
+/// Product name devices report, like `355 35549`, split into what it encodes
+///
+/// Clones sharing VID and PID could be told apart by it some day. It doesn't pick the kind yet,
+/// because every name collected so far belongs to the kind its VID and PID give anyway,
+/// so it's only logged and listed with `--list-devices`, to be collected from issue reports
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    pub family: String,
+    pub code: String,
+    pub revision: Option<String>,
+}
+
+/// Parses product name made of family prefix, numeric code and optional revision suffix,
+/// returning [None] for names in other formats
+pub fn parse(name: &str) -> Option<Identity> {
+    let mut parts = name.split_whitespace();
+
+    let family = parts.next().filter(|part| is_alphanumeric(part))?;
+    let code = parts
+        .next()
+        .filter(|part| part.chars().all(|c| c.is_ascii_digit()))?;
+    let revision = parts.next();
+
+    if parts.next().is_some() || revision.is_some_and(|revision| !is_alphanumeric(revision)) {
+        return None;
+    }
+
+    Some(Identity {
+        family: family.to_string(),
+        code: code.to_string(),
+        revision: revision.map(str::to_string),
+    })
+}
+
+fn is_alphanumeric(part: &str) -> bool {
+    part.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names reported by devices in issue reports, add new ones as clones are reported
+    const KNOWN_NAMES: [(&str, &str, &str, Option<&str>); 1] =
+        [("355 35549", "355", "35549", None)];
+
+    #[test]
+    fn known_names_are_parsed() {
+        for (name, family, code, revision) in KNOWN_NAMES {
+            assert_eq!(
+                parse(name),
+                Some(Identity {
+                    family: family.to_string(),
+                    code: code.to_string(),
+                    revision: revision.map(str::to_string),
+                }),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn revision_suffix_is_parsed() {
+        let identity = parse("355 35549 V2").unwrap();
+
+        assert_eq!(identity.family, "355");
+        assert_eq!(identity.code, "35549");
+        assert_eq!(identity.revision.as_deref(), Some("V2"));
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        assert_eq!(parse("  355   35549 "), parse("355 35549"));
+    }
+
+    #[test]
+    fn other_formats_are_rejected() {
+        for name in [
+            "",
+            "355",
+            "USB HID Device",
+            "355 3554A",
+            "355 35549 V2 extra",
+            "355 35549 V-2",
+            "35-5 35549",
+        ] {
+            assert_eq!(parse(name), None, "{}", name);
+        }
+    }
+}
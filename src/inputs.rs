@@ -1,9 +1,17 @@
 use mirajazz::{error::MirajazzError, types::DeviceInput};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::mappings::KEY_COUNT;
 
+/// Skips logging on the input path, so key presses reach OpenDeck as soon as possible
+pub static LOW_LATENCY: AtomicBool = AtomicBool::new(false);
+
+// Only digital key state is known for now. Pressure-sensitive variants need a capture of their input reports first,
+// and OpenDeck has no event to forward pressure with, so analog values would need a new event on its side too
 pub fn process_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    log::info!("Processing input: {}, {}", input, state);
+    if !LOW_LATENCY.load(Ordering::Relaxed) {
+        log::info!("Processing input: {}, {}", input, state);
+    }
 
     match input as usize {
         (0..=KEY_COUNT) => read_button_press(input, state),
@@ -21,24 +29,21 @@ fn read_button_states(states: &[u8]) -> Vec<bool> {
     bools
 }
 
-/// Converts opendeck key index to device key index
+/// Converts opendeck key index to device key index, keys outside of the 3x6 matrix are kept as is
 pub fn opendeck_to_device(key: u8) -> u8 {
-    if key < KEY_COUNT as u8 {
-        [12, 9, 6, 3, 0, 15, 13, 10, 7, 4, 1, 16, 14, 11, 8, 5, 2, 17][key as usize]
-    } else {
-        key
-    }
+    const DEVICE_KEYS: [u8; 18] = [12, 9, 6, 3, 0, 15, 13, 10, 7, 4, 1, 16, 14, 11, 8, 5, 2, 17];
+
+    DEVICE_KEYS.get(key as usize).copied().unwrap_or(key)
 }
 
-/// Converts device key index to opendeck key index
+/// Converts device key index to opendeck key index, keys outside of the 3x6 matrix are kept as is
 pub fn device_to_opendeck(key: usize) -> usize {
+    const OPENDECK_KEYS: [usize; 18] =
+        [4, 10, 16, 3, 9, 15, 2, 8, 14, 1, 7, 13, 0, 6, 12, 5, 11, 17];
+
     let key = key - 1; // We have to subtract 1 from key index reported by device, because list is shifted by 1
 
-    if key < KEY_COUNT {
-        [4, 10, 16, 3, 9, 15, 2, 8, 14, 1, 7, 13, 0, 6, 12, 5, 11, 17][key]
-    } else {
-        key
-    }
+    OPENDECK_KEYS.get(key).copied().unwrap_or(key)
 }
 
 fn read_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
@@ -55,9 +60,60 @@ fn read_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError>
 
     // `device_to_opendeck` is 0-based, so add 1
     // I'll probably have to refactor all of this off-by-one stuff in this file, but that's a future me problem
-    button_states[pressed_index + 1] = state;
+    let Some(slot) = button_states.get_mut(pressed_index + 1) else {
+        log::warn!("Device reported press of unknown key {}", input);
+
+        return Err(MirajazzError::BadData);
+    };
+
+    *slot = state;
 
     Ok(DeviceInput::ButtonStateChange(read_button_states(
         &button_states,
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(input: DeviceInput) -> Vec<usize> {
+        let DeviceInput::ButtonStateChange(states) = input else {
+            panic!("Not a button state change");
+        };
+
+        states
+            .iter()
+            .enumerate()
+            .filter(|(_, pressed)| **pressed)
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn presses_of_every_key_are_read() {
+        let DeviceInput::ButtonStateChange(released) = process_input(0, 0).unwrap() else {
+            panic!("Not a button state change");
+        };
+        assert_eq!(released, vec![false; KEY_COUNT]);
+
+        for input in 1..=KEY_COUNT as u8 {
+            let key = device_to_opendeck(input as usize);
+
+            assert_eq!(pressed(process_input(input, 1).unwrap()), [key]);
+        }
+
+        assert!(process_input(KEY_COUNT as u8 + 1, 1).is_err());
+        assert!(process_input(u8::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn key_mapping_round_trips() {
+        for key in 0..32 {
+            assert_eq!(
+                device_to_opendeck(opendeck_to_device(key) as usize + 1),
+                key as usize
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    identity::{self, Identity},
+    mappings::Capabilities,
+    stats,
+    watcher::get_candidates,
+};
+
+/// Connected device, as it's listed
+#[derive(Serialize)]
+struct Listed {
+    id: String,
+    kind: String,
+    name: String,
+    capabilities: Capabilities,
+    /// What product name device reports encodes, if it's in a known format
+    identity: Option<Identity>,
+    /// Saved key press counters by OpenDeck position, if asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presses: Option<Vec<u64>>,
+}
+
+/// Prints connected devices with what they support, and their key press counters if asked to,
+/// as JSON if asked to, returning the exit code
+pub async fn run(json: bool, with_stats: bool) -> i32 {
+    let candidates = match get_candidates().await {
+        Ok(candidates) => candidates,
+        Err(err) => {
+            eprintln!("Unable to list devices: {}", err);
+            return 1;
+        }
+    };
+
+    let mut presses = if with_stats {
+        stats::saved_presses().await
+    } else {
+        HashMap::new()
+    };
+
+    let listed: Vec<Listed> = candidates
+        .iter()
+        .map(|candidate| Listed {
+            id: candidate.id.clone(),
+            kind: format!("{:?}", candidate.kind),
+            name: candidate.kind.human_name(),
+            capabilities: candidate.kind.capabilities(),
+            identity: identity::parse(&candidate.dev.name),
+            presses: with_stats.then(|| presses.remove(&candidate.id).unwrap_or_default()),
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listed).unwrap()); // Plain structs always serialize
+
+        return 0;
+    }
+
+    if listed.is_empty() {
+        println!("No supported devices connected");
+    }
+
+    for device in listed {
+        let capabilities = device.capabilities;
+
+        println!(
+            "{} ({}, {}): protocol v{}, {} keys in {}x{}, {} encoders",
+            device.id,
+            device.name,
+            device.kind,
+            capabilities.protocol_version,
+            capabilities.keys,
+            capabilities.rows,
+            capabilities.columns,
+            capabilities.encoders
+        );
+
+        if let Some(presses) = device.presses {
+            println!("  Key presses: {}", format_presses(&presses));
+        }
+    }
+
+    0
+}
+
+/// Formats counters of the pressed keys, like `key 0: 12, key 5: 3`
+fn format_presses(presses: &[u64]) -> String {
+    let pressed: Vec<String> = presses
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(key, count)| format!("key {}: {}", key, count))
+        .collect();
+
+    if pressed.is_empty() {
+        return "none".to_string();
+    }
+
+    pressed.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pressed_keys_are_listed() {
+        assert_eq!(format_presses(&[12, 0, 0, 0, 0, 3]), "key 0: 12, key 5: 3");
+        assert_eq!(format_presses(&[0; 18]), "none");
+        assert_eq!(format_presses(&[]), "none");
+    }
+}
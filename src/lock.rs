@@ -0,0 +1,213 @@
+use std::fmt;
+
+#[cfg(target_os = "linux")]
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Lockfile claiming the device or the whole plugin for this process, released when dropped
+///
+/// Another plugin instance (e.g. a fork installed next to this plugin) opening the same hidraw node
+/// would split events and interleave image data, so only the process holding the lock talks to the device.
+/// Lock is held by the kernel for as long as the file is open, so locks of crashed processes are released
+/// with them, and the file itself is never removed, because another process may be waiting to lock it
+pub struct Lock {
+    #[cfg(target_os = "linux")]
+    _file: Option<File>,
+}
+
+/// Process holding the lock, as it described itself in the lockfile
+#[derive(Debug, Clone, PartialEq)]
+pub struct Holder {
+    /// Missing if the holder didn't write the lockfile yet
+    pub pid: Option<u32>,
+    pub binary: String,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "PID {}, {}", pid, self.binary),
+            None => write!(f, "process that is just starting"),
+        }
+    }
+}
+
+/// Returns path of the lockfile for the device, in the runtime dir shared by all the plugin processes
+#[cfg(target_os = "linux")]
+fn lock_path(id: &str) -> PathBuf {
+    let name: String = id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    crate::paths::runtime_dir().join(format!("opendeck-akp153-{}.lock", name))
+}
+
+/// Claims the device, returning process holding it if it's taken
+#[cfg(target_os = "linux")]
+pub fn acquire(id: &str) -> Result<Lock, Holder> {
+    claim(&lock_path(id))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn acquire(_id: &str) -> Result<Lock, Holder> {
+    // Not implemented for other platforms yet
+    Ok(Lock {})
+}
+
+/// Claims the plugin for this process, returning the live instance holding it if it's taken
+///
+/// Plugin installed twice under different ids is started twice by OpenDeck, and both copies would
+/// send every key event
+#[cfg(target_os = "linux")]
+pub fn acquire_instance() -> Result<Lock, Holder> {
+    claim(&crate::paths::runtime_dir().join("opendeck-akp153.lock"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn acquire_instance() -> Result<Lock, Holder> {
+    // Not implemented for other platforms yet
+    Ok(Lock {})
+}
+
+/// Locks the file, writing PID and binary of this process into it, or returns the process holding it
+#[cfg(target_os = "linux")]
+fn claim(path: &Path) -> Result<Lock, Holder> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path);
+
+    // Locking is advisory, so plugin keeps going if runtime dir is not writable
+    let mut file = match file {
+        Ok(file) => file,
+        Err(err) => {
+            log::warn!("Unable to open {}: {}", path.display(), err);
+            return Ok(Lock { _file: None });
+        }
+    };
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Err(read_holder(&mut file)),
+        Err(TryLockError::Error(err)) => {
+            log::warn!("Unable to lock {}: {}", path.display(), err);
+            return Ok(Lock { _file: None });
+        }
+    }
+
+    // Contents are only written once the file is locked, so they always belong to the holder
+    let binary = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+
+    let written = file
+        .set_len(0)
+        .and_then(|_| write!(file, "{}\n{}", std::process::id(), binary));
+
+    if let Err(err) = written {
+        log::warn!("Unable to write {}: {}", path.display(), err);
+    }
+
+    Ok(Lock { _file: Some(file) })
+}
+
+#[cfg(target_os = "linux")]
+fn read_holder(file: &mut File) -> Holder {
+    let mut held = String::new();
+    file.read_to_string(&mut held).ok();
+
+    let (pid, binary) = held.split_once('\n').unwrap_or((&held, ""));
+
+    Holder {
+        pid: pid.trim().parse().ok(),
+        binary: binary.trim().to_string(),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn lock_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "opendeck-akp153-test-{}-{}.lock",
+            std::process::id(),
+            name
+        ));
+        std::fs::remove_file(&path).ok();
+
+        path
+    }
+
+    #[test]
+    fn held_lock_names_its_holder() {
+        let path = lock_file("held");
+        let _lock = claim(&path).unwrap();
+
+        let holder = claim(&path).err().unwrap();
+
+        assert_eq!(holder.pid, Some(std::process::id()));
+        assert!(!holder.binary.is_empty());
+    }
+
+    #[test]
+    fn released_lock_can_be_claimed_again() {
+        let path = lock_file("released");
+
+        drop(claim(&path).unwrap());
+
+        assert!(claim(&path).is_ok());
+    }
+
+    #[test]
+    fn lockfile_left_by_dead_process_is_claimed() {
+        let path = lock_file("stale");
+        std::fs::write(&path, "4194304\n/old/plugin").unwrap();
+
+        let _lock = claim(&path).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(&format!("{}\n", std::process::id())));
+    }
+
+    #[test]
+    fn only_one_of_instances_started_together_gets_the_lock() {
+        let path = lock_file("instances");
+
+        let claims: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || claim(&path))
+            })
+            .collect();
+
+        let claims: Vec<_> = claims
+            .into_iter()
+            .map(|claim| claim.join().unwrap())
+            .collect();
+
+        assert_eq!(claims.iter().filter(|claim| claim.is_ok()).count(), 1);
+    }
+
+    #[test]
+    fn empty_lockfile_of_live_holder_is_not_broken() {
+        let path = lock_file("empty");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.lock().unwrap();
+
+        let holder = claim(&path).err().unwrap();
+
+        assert_eq!(holder.pid, None);
+    }
+}
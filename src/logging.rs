@@ -0,0 +1,151 @@
+use simplelog::{ColorChoice, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger};
+use std::{
+    fs::File,
+    str::FromStr,
+    sync::{LazyLock, RwLock},
+};
+
+use crate::paths::state_file;
+
+/// File logs are copied to when enabled, next to the other plugin state
+pub const LOG_FILE: &str = "plugin.log";
+
+/// Level used if settings don't specify one
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct Filter {
+    level: LevelFilter,
+    /// Modules to log, everything is logged if empty
+    modules: Vec<String>,
+}
+
+/// Logger which filter and targets can be changed while the plugin is running
+struct Logger {
+    term: Box<TermLogger>,
+    file: RwLock<Option<Box<WriteLogger<File>>>>,
+    filter: RwLock<Filter>,
+}
+
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger {
+    // Inner loggers log everything, filtering is done by the outer one
+    term: TermLogger::new(
+        LevelFilter::Trace,
+        Config::default(),
+        TerminalMode::Stdout,
+        ColorChoice::Never,
+    ),
+    file: RwLock::new(None),
+    filter: RwLock::new(Filter {
+        level: DEFAULT_LEVEL,
+        modules: vec![],
+    }),
+});
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // Errors are always logged, no matter the filter
+        if metadata.level() == log::Level::Error {
+            return true;
+        }
+
+        let filter = self.filter.read().unwrap();
+
+        metadata.level() <= filter.level
+            && (filter.modules.is_empty()
+                || filter
+                    .modules
+                    .iter()
+                    .any(|module| metadata.target().starts_with(module.as_str())))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        self.term.log(record);
+
+        if let Some(file) = self.file.read().unwrap().as_ref() {
+            file.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.term.flush();
+
+        if let Some(file) = self.file.read().unwrap().as_ref() {
+            file.flush();
+        }
+    }
+}
+
+/// Installs the logger with the default level
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&*LOGGER)?;
+    log::set_max_level(DEFAULT_LEVEL);
+
+    Ok(())
+}
+
+/// Applies level, module filters and file target, taking effect immediately
+pub fn configure(level: Option<&str>, modules: &[String], to_file: bool) {
+    let level = match level.map(LevelFilter::from_str) {
+        None => DEFAULT_LEVEL,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            log::error!("Unknown log level {:?}, using {}", level, DEFAULT_LEVEL);
+            DEFAULT_LEVEL
+        }
+    };
+
+    // Turning logs off would hide errors too
+    let level = level.max(LevelFilter::Error);
+
+    *LOGGER.filter.write().unwrap() = Filter {
+        level,
+        modules: modules.to_vec(),
+    };
+    log::set_max_level(level);
+
+    // Logging while holding the lock would deadlock, so file is opened beforehand
+    let target = if to_file && LOGGER.file.read().unwrap().is_none() {
+        match File::create(state_file(LOG_FILE)) {
+            Ok(target) => Some(target),
+            Err(err) => {
+                log::error!("Unable to create {}: {}", LOG_FILE, err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut file = LOGGER.file.write().unwrap();
+
+    if let Some(target) = target {
+        *file = Some(WriteLogger::new(
+            LevelFilter::Trace,
+            Config::default(),
+            target,
+        ));
+    } else if !to_file {
+        *file = None;
+    }
+
+    drop(file);
+
+    log::info!(
+        "Logging at {} level{}{}",
+        level,
+        if modules.is_empty() {
+            String::new()
+        } else {
+            format!(" for {}", modules.join(", "))
+        },
+        if to_file {
+            format!(", copying to {}", state_file(LOG_FILE).display())
+        } else {
+            String::new()
+        }
+    );
+}
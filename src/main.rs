@@ -1,94 +1,579 @@
-use device::{handle_error, handle_set_image};
-use mirajazz::device::Device;
+use cache::{DEFAULT_CACHE_SIZE, ImageCache};
+use device::{device_kind, handle_error, handle_set_image, identify, ping, set_brightness};
+use mappings::{KEY_COUNT, Kind};
+use mirajazz::{device::Device, error::MirajazzError};
 use openaction::*;
-use std::{collections::HashMap, process::exit, sync::LazyLock};
-use tokio::sync::{Mutex, RwLock};
+use outbound::{OpenDeck, Outbound};
+use ratelimit::RateLimiter;
+use settings::{Settings, SimulatedPress};
+use stats::stats_task;
+use std::{
+    collections::{HashMap, HashSet},
+    process::exit,
+    sync::{LazyLock, OnceLock},
+    time::Duration,
+};
+use tokio::sync::{Mutex, Notify, RwLock};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use watcher::watcher_task;
 
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{SignalKind, signal};
 
+mod aliases;
+mod backlog;
+mod cache;
 mod device;
+mod eco;
+mod encode;
+mod fade;
+mod identity;
 mod inputs;
+mod listing;
+mod lock;
+mod logging;
 mod mappings;
+mod memory;
+mod outbound;
+mod paths;
+mod permissions;
+mod pipeline;
+mod power;
+mod pressed;
+mod ratelimit;
+mod registration;
+mod report;
+mod screen;
+mod selfcheck;
+mod settings;
+mod smoketest;
+mod stats;
+mod status;
+mod sysfs;
+mod trace;
+mod watchdog;
 mod watcher;
 
 pub static DEVICES: LazyLock<RwLock<HashMap<String, Device>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static KINDS: LazyLock<RwLock<HashMap<String, Kind>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static BRIGHTNESS: LazyLock<RwLock<HashMap<String, u8>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static RESET_TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static IMAGE_CACHE: LazyLock<Mutex<ImageCache>> =
+    LazyLock::new(|| Mutex::new(ImageCache::new(DEFAULT_CACHE_SIZE)));
+pub static OUT_OF_RANGE_WARNED: LazyLock<RwLock<HashSet<String>>> =
+    LazyLock::new(|| RwLock::new(HashSet::new()));
 pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
+pub static SETTINGS: LazyLock<RwLock<Settings>> =
+    LazyLock::new(|| RwLock::new(Settings::default()));
+
+/// Events for unknown devices logged recently, by device id
+static UNKNOWN_DEVICE_LOGS: LazyLock<Mutex<RateLimiter<String>>> =
+    LazyLock::new(|| Mutex::new(RateLimiter::new(ratelimit::BURST, ratelimit::WINDOW)));
+
+/// Wakes watcher up once settings are received, so devices present at startup are set up with them
+static SETTINGS_RECEIVED: Notify = Notify::const_new();
+
+/// How long OpenDeck is waited for to send settings before devices are set up with the defaults
+const SETTINGS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Explanation of why devices are left alone, if another instance of the plugin is running
+static DUPLICATE_INSTANCE: OnceLock<String> = OnceLock::new();
 
 struct GlobalEventHandler {}
 impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn plugin_ready(
         &self,
-        _outbound: &mut openaction::OutboundEventManager,
+        outbound: &mut openaction::OutboundEventManager,
     ) -> EventHandlerResult {
+        outbound.get_global_settings().await?;
+
         let tracker = TRACKER.lock().await.clone();
 
+        if let Some(message) = DUPLICATE_INSTANCE.get() {
+            outbound::notify_later(message.clone()).await;
+        } else {
+            let token = CancellationToken::new();
+            tracker.spawn(wait_for_settings(token.clone()));
+
+            TOKENS
+                .write()
+                .await
+                .insert("_watcher_task".to_string(), token);
+        }
+
         let token = CancellationToken::new();
-        tracker.spawn(watcher_task(token.clone()));
+        tracker.spawn(stats_task(token.clone()));
 
         TOKENS
             .write()
             .await
-            .insert("_watcher_task".to_string(), token);
+            .insert("_stats_task".to_string(), token);
+
+        let token = CancellationToken::new();
+        tracker.spawn(eco::eco_task(token.clone()));
+
+        TOKENS.write().await.insert("_eco_task".to_string(), token);
 
         log::info!("Plugin initialized");
 
         Ok(())
     }
 
-    async fn set_image(
+    async fn system_did_wake_up(
         &self,
-        event: SetImageEvent,
+        _event: SystemDidWakeUpEvent,
         _outbound: &mut OutboundEventManager,
     ) -> EventHandlerResult {
-        log::debug!("Asked to set image: {:#?}", event);
+        log::info!("System woke up");
 
-        // Skip knobs images
-        if event.controller == Some("Encoder".to_string()) {
-            log::debug!("Looks like a knob, no need to set image");
-            return Ok(());
-        }
+        eco::activity().await;
 
-        let id = event.device.clone();
+        Ok(())
+    }
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            handle_set_image(device, event)
+    async fn did_receive_global_settings(
+        &self,
+        event: DidReceiveGlobalSettingsEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        log::debug!("Received settings: {:#?}", event.payload.settings);
+
+        if let Some(settings) = Settings::from_value(event.payload.settings) {
+            logging::configure(
+                settings.log_level.as_deref(),
+                &settings.log_modules,
+                settings.log_to_file,
+            );
+
+            inputs::LOW_LATENCY.store(settings.low_latency, std::sync::atomic::Ordering::Relaxed);
+            trace::TIMINGS.store(settings.trace_timings, std::sync::atomic::Ordering::Relaxed);
+
+            encode::set_limit(
+                settings
+                    .max_concurrent_encodes
+                    .unwrap_or_else(encode::default_limit),
+            );
+
+            IMAGE_CACHE
+                .lock()
                 .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
+                .set_limit(settings.image_cache_size.unwrap_or(DEFAULT_CACHE_SIZE));
+
+            encode::set_transform_cache_limit(
+                settings
+                    .transform_cache_size
+                    .unwrap_or(encode::DEFAULT_TRANSFORM_CACHE_SIZE),
+            )
+            .await;
+
+            memory::set_limit(
+                settings
+                    .memory_limit
+                    .unwrap_or(memory::DEFAULT_MEMORY_LIMIT),
+            )
+            .await;
+
+            let previous = std::mem::replace(&mut *SETTINGS.write().await, settings.clone());
+
+            // OpenDeck connection is locked while handling events, so (de)register devices in a separate task
+            TRACKER.lock().await.spawn(update_mirror_registrations(
+                previous.clone(),
+                settings.clone(),
+            ));
+
+            TRACKER
+                .lock()
+                .await
+                .spawn(refresh_images(previous.clone(), settings.clone()));
+
+            reconnect_overridden(&previous, &settings).await;
+            update_exclusions(&previous, &settings).await;
+            pause_devices(&previous, &settings).await;
+
+            if settings.report && !previous.report {
+                // Listing devices takes a while, so don't block other events
+                TRACKER.lock().await.spawn(report_task());
+            }
+
+            if settings.reset_press_counters && !previous.reset_press_counters {
+                stats::reset().await;
+            }
+
+            if let Some(id) = settings
+                .identify
+                .filter(|id| previous.identify.as_ref() != Some(id))
+            {
+                // Blinking takes a while, so don't block other events
+                TRACKER.lock().await.spawn(identify_task(id));
+            }
+
+            if let Some(press) = settings
+                .simulate_press
+                .filter(|press| previous.simulate_press.as_ref() != Some(press))
+            {
+                TRACKER.lock().await.spawn(simulate_press(press));
+            }
+
+            if let Some(id) = settings
+                .ping
+                .filter(|id| previous.ping.as_ref() != Some(id))
+            {
+                TRACKER.lock().await.spawn(ping_device(id));
+            }
         }
 
+        // Malformed settings are reported above, devices are set up with the defaults then
+        SETTINGS_RECEIVED.notify_one();
+
         Ok(())
     }
 
+    async fn set_image(
+        &self,
+        event: SetImageEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        trace::traced(handle_image_event(event)).await
+    }
+
     async fn set_brightness(
         &self,
         event: SetBrightnessEvent,
         _outbound: &mut OutboundEventManager,
     ) -> EventHandlerResult {
-        log::debug!("Asked to set brightness: {:#?}", event);
+        handle_brightness_event(event).await
+    }
+}
+
+/// Sets brightness of the device and the ones mirroring it, remembering it for the ones not connected yet
+async fn handle_brightness_event(event: SetBrightnessEvent) -> EventHandlerResult {
+    log::debug!("Asked to set brightness: {:#?}", event);
+
+    let id = event.device.clone();
+
+    // Remember the value, so it can be restored when device reconnects
+    BRIGHTNESS
+        .write()
+        .await
+        .insert(event.device.clone(), event.brightness);
+
+    if let Some(device) = DEVICES.read().await.get(&event.device) {
+        set_brightness(&id, device, event.brightness)
+            .await
+            .map_err(async |err| handle_error(&id, err).await)
+            .ok();
+    } else {
+        // OpenDeck sends brightness of the devices it knows before they are connected
+        log::info!(
+            "Device {} is not connected yet, its brightness will be set once it is",
+            event.device
+        );
+    }
+
+    let members = SETTINGS.read().await.mirror_members(&event.device).to_vec();
 
-        let id = event.device.clone();
+    for member in members {
+        BRIGHTNESS
+            .write()
+            .await
+            .insert(member.clone(), event.brightness);
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            device
-                .set_brightness(event.brightness)
+        if let Some(device) = DEVICES.read().await.get(&member) {
+            set_brightness(&member, device, event.brightness)
                 .await
-                .map_err(async |err| handle_error(&id, err).await)
+                .map_err(async |err| handle_error(&member, err).await)
                 .ok();
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts watching for devices once settings are received, because they decide how devices are set up
+async fn wait_for_settings(token: CancellationToken) -> Result<(), MirajazzError> {
+    tokio::select! {
+        _ = SETTINGS_RECEIVED.notified() => {},
+        _ = tokio::time::sleep(SETTINGS_TIMEOUT) => {
+            log::warn!("OpenDeck didn't send settings in {:?}, using the defaults", SETTINGS_TIMEOUT);
+        },
+        _ = token.cancelled() => return Ok(())
+    }
+
+    watcher_task(token).await
+}
+
+/// Deregisters devices that started mirroring other devices, and registers the ones that stopped
+async fn update_mirror_registrations(previous: Settings, settings: Settings) {
+    // Registration can take a while and needs OpenDeck connection, so devices are not held while talking to it
+    let mut devices = Vec::new();
+
+    for (id, device) in DEVICES.read().await.iter() {
+        devices.push((id.clone(), device_kind(id, device).await));
+    }
+
+    for (id, kind) in devices {
+        let was_mirroring = previous.mirror_primary(&id).is_some();
+        let primary = settings.mirror_primary(&id);
+
+        if let Some(primary) =
+            primary.filter(|primary| previous.mirror_primary(&id) != Some(*primary))
+        {
+            if !was_mirroring {
+                log::info!("Device {} joined a mirror group, deregistering it", id);
+                registration::deregister(&id, &OpenDeck).await;
+            }
+
+            // Images only reach the member when OpenDeck sends them to the primary
+            OpenDeck.rerender_images(primary).await.ok();
+        } else if was_mirroring && primary.is_none() {
+            log::info!("Device {} left a mirror group, registering it", id);
+            registration::register(&id, &kind, &OpenDeck).await;
+        }
+    }
+}
+
+/// Resets devices which kind override or extended init changed, so they are connected again with the new settings
+async fn reconnect_overridden(previous: &Settings, settings: &Settings) {
+    for (id, reset) in RESET_TOKENS.read().await.iter() {
+        if previous.kind_overrides.get(id) != settings.kind_overrides.get(id) {
+            log::info!("Kind override of {} changed, reconnecting it", id);
+            reset.cancel();
+        } else if previous.extended_init.get(id) != settings.extended_init.get(id) {
+            log::info!("Extended init of {} changed, reconnecting it", id);
+            reset.cancel();
+        }
+    }
+}
+
+/// Closes devices that got paused, the ones that are resumed are opened again by their device tasks
+async fn pause_devices(previous: &Settings, settings: &Settings) {
+    for (id, reset) in RESET_TOKENS.read().await.iter() {
+        if settings.paused_devices.contains(id) && !previous.paused_devices.contains(id) {
+            reset.cancel();
+        }
+    }
+}
+
+/// Stops devices that got excluded, and looks for the ones that are no longer excluded
+async fn update_exclusions(previous: &Settings, settings: &Settings) {
+    for id in settings
+        .excluded_devices
+        .difference(&previous.excluded_devices)
+    {
+        if let Some(token) = TOKENS.write().await.remove(id) {
+            log::info!("Device {} is excluded, stopping it", id);
+            token.cancel();
+        }
+    }
+
+    if previous
+        .excluded_devices
+        .difference(&settings.excluded_devices)
+        .next()
+        .is_some()
+    {
+        watcher::rescan();
+    }
+}
+
+/// Asks OpenDeck to send images again for devices which images are processed differently now
+async fn refresh_images(previous: Settings, settings: Settings) {
+    let ids: HashSet<String> = DEVICES
+        .read()
+        .await
+        .keys()
+        .filter(|id| settings.image_options_changed(&previous, id))
+        // Only devices registered in OpenDeck can be rerendered, and members get images of their primary
+        .map(|id| settings.mirror_primary(id).unwrap_or(id).clone())
+        .collect();
+
+    for id in ids {
+        log::info!(
+            "Image settings of {} changed, requesting all the images",
+            id
+        );
+
+        OpenDeck.rerender_images(&id).await.ok();
+    }
+}
+
+/// Writes issue report, telling where it is to the log and OpenDeck
+async fn report_task() {
+    let message = match report::write().await {
+        Ok(path) => format!("Issue report is written to {}", path),
+        Err(err) => format!("Unable to write issue report: {}", err),
+    };
+
+    log::info!("{}", message);
+
+    OpenDeck.notify(message).await.ok();
+}
+
+/// Checks if device is reachable, reporting result to the log and OpenDeck
+async fn ping_device(id: String) {
+    let result = match DEVICES.read().await.get(&id) {
+        Some(device) => ping(device).await,
+        None => Err(MirajazzError::DeviceNotFoundError),
+    };
+
+    let message = match result {
+        Ok(time) => format!("Device {} is reachable, took {:?}", id, time),
+        Err(err) => format!("Device {} is unreachable: {}", id, err),
+    };
+
+    log::info!("{}", message);
+
+    OpenDeck.notify(message).await.ok();
+}
+
+/// How long simulated press holds the key, unless it says otherwise
+const SIMULATED_PRESS_DURATION: Duration = Duration::from_millis(100);
+
+/// Presses and releases the key in OpenDeck, as if it was pressed on the device
+async fn simulate_press(press: SimulatedPress) {
+    let key_count = match DEVICES.read().await.get(&press.device) {
+        Some(device) => device_kind(&press.device, device).await.key_count(),
+        None => {
+            log::error!("Asked to press key of unknown device: {}", press.device);
+            return;
+        }
+    };
+
+    if press.key as usize >= key_count {
+        log::error!(
+            "Asked to press key {} of {}, it only has {} keys",
+            press.key,
+            press.device,
+            key_count
+        );
+        return;
+    }
+
+    log::info!("Simulating press of key {} of {}", press.key, press.device);
+
+    OpenDeck.key_down(&press.device, press.key).await.ok();
+
+    tokio::time::sleep(
+        press
+            .duration
+            .map(Duration::from_millis)
+            .unwrap_or(SIMULATED_PRESS_DURATION),
+    )
+    .await;
+
+    OpenDeck.key_up(&press.device, press.key).await.ok();
+}
+
+async fn identify_task(id: String) {
+    if !DEVICES.read().await.contains_key(&id) {
+        log::error!("Asked to identify unknown device: {}", id);
+        return;
+    }
+
+    if let Err(err) = identify(&id).await {
+        handle_error(&id, err).await;
+    }
+}
+
+/// Returns position of the image event if it's beyond the keys of the device
+///
+/// Events without position are for the whole device, they clear it or set the wallpaper.
+/// Negative positions and other malformed events never get here, they fail to parse in the first place
+fn out_of_range(event: &SetImageEvent, key_count: usize) -> Option<u8> {
+    event
+        .position
+        .filter(|position| *position as usize >= key_count)
+}
+
+/// Checks if images for keys beyond the device weren't dropped for it yet
+///
+/// Warning is only logged once per device, because OpenDeck would send the same event on every profile switch
+async fn first_out_of_range(device: &str) -> bool {
+    OUT_OF_RANGE_WARNED.write().await.insert(device.to_string())
+}
+
+/// Sets image to the device and the ones mirroring it
+async fn handle_image_event(event: SetImageEvent) -> EventHandlerResult {
+    log::debug!("Asked to set image (trace {}): {:#?}", trace::id(), event);
+
+    // Skip knobs images
+    if event.controller == Some("Encoder".to_string()) {
+        log::debug!("Looks like a knob, no need to set image");
+        return Ok(());
+    }
+
+    let key_count = match DEVICES.read().await.get(&event.device) {
+        Some(device) => device_kind(&event.device, device).await.key_count(),
+        None => KEY_COUNT,
+    };
+
+    if let Some(position) = out_of_range(&event, key_count) {
+        if first_out_of_range(&event.device).await {
+            log::warn!(
+                "Dropping image for key {} of device {}, it only has {} keys, profile is probably made for a bigger device",
+                position,
+                event.device,
+                key_count
+            );
         } else {
-            log::error!("Received event for unknown device: {}", event.device);
+            log::debug!("Dropping image for key {} of {}", position, event.device);
         }
 
-        Ok(())
+        stats::count_dropped(&event.device, position).await;
+
+        return Ok(());
+    }
+
+    let members = SETTINGS.read().await.mirror_members(&event.device).to_vec();
+
+    let id = event.device.clone();
+
+    if SETTINGS.read().await.paused_devices.contains(&event.device) {
+        log::debug!(
+            "Device {} is paused, dropping image (trace {})",
+            event.device,
+            trace::id()
+        );
+        stats::count_drop(&event.device, "images while paused").await;
+    } else if let Some(device) = DEVICES.read().await.get(&event.device) {
+        handle_set_image(device, event.clone())
+            .await
+            .map_err(async |err| handle_error(&id, err).await)
+            .ok();
+    } else {
+        let check = UNKNOWN_DEVICE_LOGS.lock().await.check(event.device.clone());
+
+        if let Some(suppressed) = check {
+            log::error!(
+                "Received event for unknown device: {} (trace {}){}",
+                event.device,
+                trace::id(),
+                ratelimit::suppressed_suffix(suppressed)
+            );
+        }
+        stats::count_drop(&event.device, "images for disconnected device").await;
+    }
+
+    // Devices mirroring this one get exactly the same images
+    for member in members {
+        if let Some(device) = DEVICES.read().await.get(&member) {
+            let mut event = event.clone();
+            event.device = member.clone();
+
+            handle_set_image(device, event)
+                .await
+                .map_err(async |err| handle_error(&member, err).await)
+                .ok();
+        }
     }
+
+    Ok(())
 }
 
 struct ActionEventHandler {}
@@ -130,13 +615,73 @@ async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    simplelog::TermLogger::init(
-        simplelog::LevelFilter::Info,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Stdout,
-        simplelog::ColorChoice::Never,
-    )
-    .unwrap();
+    logging::init().unwrap();
+
+    // Report mode writes the report and exits, without connecting to OpenDeck
+    if std::env::args().any(|arg| arg == "--report") {
+        match report::write().await {
+            Ok(path) => println!("Issue report is written to {}", path),
+            Err(err) => println!("Unable to write issue report: {}", err),
+        }
+
+        return Ok(());
+    }
+
+    // Smoke test mode drives a device without OpenDeck, failing with a code of the stage that failed
+    if std::env::args().any(|arg| arg == "--smoke-test") {
+        let args: Vec<String> = std::env::args().collect();
+        let id = args
+            .iter()
+            .position(|arg| arg == "--device")
+            .and_then(|index| args.get(index + 1));
+
+        exit(smoketest::run(id.map(String::as_str)).await);
+    }
+
+    // Listing mode prints connected devices with what they support, without connecting to OpenDeck
+    if std::env::args().any(|arg| arg == "--list-devices") {
+        exit(
+            listing::run(
+                std::env::args().any(|arg| arg == "--json"),
+                std::env::args().any(|arg| arg == "--stats"),
+            )
+            .await,
+        );
+    }
+
+    // Self-check mode checks image formats and exits, failing if any of them is wrong
+    if std::env::args().any(|arg| arg == "--self-check") {
+        exit(if selfcheck::run() { 0 } else { 1 });
+    }
+
+    // Check is cheap, but there is no need to repeat it on every start of release builds
+    if cfg!(debug_assertions) {
+        selfcheck::run();
+    }
+
+    // Held until plugin exits, plugin stays connected to OpenDeck without it, but leaves devices alone
+    let _instance = match lock::acquire_instance() {
+        Ok(lock) => Some(lock),
+        Err(holder) => {
+            let message = format!(
+                "Another instance of the plugin is already running ({}), leaving devices to it. Remove one of the installed copies",
+                holder
+            );
+
+            log::error!("{}", message);
+            DUPLICATE_INSTANCE.set(message).ok();
+
+            None
+        }
+    };
+
+    // Device tasks are async and share runtime workers, only work on images takes blocking threads
+    log::info!(
+        "Running on {} cores: device tasks share {} runtime workers, up to {} images are encoded on blocking threads at the same time",
+        encode::default_limit(),
+        tokio::runtime::Handle::current().metrics().num_workers(),
+        encode::limit()
+    );
 
     tokio::select! {
         _ = connect() => {},
@@ -158,3 +703,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(value: serde_json::Value) -> Result<SetImageEvent, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    #[test]
+    fn positions_beyond_device_are_out_of_range() {
+        for position in [18, 32, u8::MAX] {
+            let event = event(serde_json::json!({"device": "99-1", "position": position})).unwrap();
+
+            assert_eq!(out_of_range(&event, 18), Some(position));
+        }
+
+        let event = event(serde_json::json!({"device": "99-1", "position": 15})).unwrap();
+        assert_eq!(out_of_range(&event, 15), Some(15));
+        assert_eq!(out_of_range(&event, 18), None);
+    }
+
+    #[test]
+    fn events_without_position_are_for_whole_device() {
+        let clear = event(serde_json::json!({"device": "99-1"})).unwrap();
+        let wallpaper =
+            event(serde_json::json!({"device": "99-1", "position": null, "image": "data:"}))
+                .unwrap();
+
+        assert_eq!(out_of_range(&clear, 18), None);
+        assert_eq!(out_of_range(&wallpaper, 18), None);
+    }
+
+    #[test]
+    fn malformed_events_are_not_parsed() {
+        for value in [
+            serde_json::json!({"device": "99-1", "position": -1}),
+            serde_json::json!({"device": "99-1", "position": 256}),
+            serde_json::json!({"device": "99-1", "position": "3"}),
+            serde_json::json!({"device": "99-1", "position": 1.5}),
+            serde_json::json!({"position": 3}),
+        ] {
+            assert!(event(value.clone()).is_err(), "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_range_images_are_reported_once_per_device() {
+        assert!(first_out_of_range("99-out-of-range-a").await);
+        assert!(!first_out_of_range("99-out-of-range-a").await);
+        assert!(first_out_of_range("99-out-of-range-b").await);
+    }
+
+    #[tokio::test]
+    async fn brightness_sent_before_connect_is_used_on_connect() {
+        let brightness = |brightness| SetBrightnessEvent {
+            device: "99-brightness-before-connect".to_string(),
+            brightness,
+        };
+
+        handle_brightness_event(brightness(30)).await.unwrap();
+        assert_eq!(
+            device::initial_brightness("99-brightness-before-connect").await,
+            30
+        );
+
+        // Only the latest value counts
+        handle_brightness_event(brightness(70)).await.unwrap();
+        assert_eq!(
+            device::initial_brightness("99-brightness-before-connect").await,
+            70
+        );
+
+        assert_eq!(
+            device::initial_brightness("99-brightness-never-sent").await,
+            50
+        );
+    }
+}
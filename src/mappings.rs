@@ -1,20 +1,27 @@
+use crate::pipeline::ColorCurves;
 use mirajazz::{
     device::DeviceQuery,
     types::{HidDeviceInfo, ImageFormat, ImageMirroring, ImageMode, ImageRotation},
 };
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 // 153 in hex is 99
 // Must be unique between all the plugins, 2 characters long and match `DeviceNamespace` field in `manifest.json`
 pub const DEVICE_NAMESPACE: &str = "99";
 
+// All the "v1" devices share the same serial
+pub const V1_SERIAL: &str = "355499441494";
+
 pub const ROW_COUNT: usize = 3;
 pub const COL_COUNT: usize = 6;
 pub const KEY_COUNT: usize = ROW_COUNT * COL_COUNT;
 pub const ENCODER_COUNT: usize = 0;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub enum Kind {
     HSV293S,
+    HSV293SCREATOR,
     HSV293SV3,
     HSV293SV3_1005,
     AKP153,
@@ -30,6 +37,25 @@ pub enum Kind {
     D15,
 }
 
+/// All the kinds, so things can be checked for each of them
+pub const ALL_KINDS: [Kind; 15] = [
+    Kind::HSV293S,
+    Kind::HSV293SCREATOR,
+    Kind::HSV293SV3,
+    Kind::HSV293SV3_1005,
+    Kind::AKP153,
+    Kind::AKP153E,
+    Kind::AKP153R,
+    Kind::AKP153EREV2,
+    Kind::AKP153RREV2,
+    Kind::MSDONE,
+    Kind::GK150K,
+    Kind::RMV01,
+    Kind::SFSTC,
+    Kind::TMICESC,
+    Kind::D15,
+];
+
 pub const AJAZZ_VID: u16 = 0x0300;
 pub const MIRABOX_VID: u16 = 0x5548;
 pub const MIRABOX_2_VID: u16 = 0x6603;
@@ -41,6 +67,7 @@ pub const TMICE_VID: u16 = 0x0500;
 pub const WOMIER_VID: u16 = 0x0600;
 
 pub const HSV293S_PID: u16 = 0x6670;
+pub const HSV293S_CREATOR_PID: u16 = 0x6671;
 pub const HSV293SV3_PID: u16 = 0x1014;
 pub const HSV293SV3_1005_PID: u16 = 0x1005;
 
@@ -50,6 +77,7 @@ pub const AKP153R_PID: u16 = 0x1020;
 pub const AKP153E_REV2_PID: u16 = 0x3010;
 pub const AKP153R_REV2_PID: u16 = 0x3011;
 
+// MSD-ONE, GK150K and D15 share the same PID under different VIDs
 pub const MSD_ONE_PID: u16 = 0x1000;
 
 pub const GK150K_PID: u16 = 0x1000;
@@ -62,6 +90,8 @@ pub const D15_PID: u16 = 0x1000;
 
 // Map all queries to usage page 65440 and usage id 1 for now
 pub const HSV293S_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, MIRABOX_VID, HSV293S_PID);
+pub const HSV293S_CREATOR_QUERY: DeviceQuery =
+    DeviceQuery::new(65440, 1, MIRABOX_VID, HSV293S_CREATOR_PID);
 pub const HSV293SV3_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, MIRABOX_2_VID, HSV293SV3_PID);
 pub const HSV293SV3_1005_QUERY: DeviceQuery =
     DeviceQuery::new(65440, 1, MIRABOX_2_VID, HSV293SV3_1005_PID);
@@ -77,8 +107,9 @@ pub const SF_STC_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, SF_STC_VID, SF_
 pub const TMICESC_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, TMICE_VID, TMICESC_PID);
 pub const D15_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, WOMIER_VID, D15_PID);
 
-pub const QUERIES: [DeviceQuery; 14] = [
+pub const QUERIES: [DeviceQuery; 15] = [
     HSV293S_QUERY,
+    HSV293S_CREATOR_QUERY,
     HSV293SV3_QUERY,
     HSV293SV3_1005_QUERY,
     AKP153_QUERY,
@@ -100,7 +131,7 @@ pub fn get_image_format_for_key(kind: &Kind, key: u8) -> ImageFormat {
         return ImageFormat {
             mode: ImageMode::JPEG,
             size: (85, 85),
-            rotation: ImageRotation::Rot90,
+            rotation: kind.image_rotation(),
             mirror: ImageMirroring::Both,
         };
     }
@@ -113,13 +144,51 @@ pub fn get_image_format_for_key(kind: &Kind, key: u8) -> ImageFormat {
     ImageFormat {
         mode: ImageMode::JPEG,
         size,
-        rotation: ImageRotation::Rot90,
+        rotation: kind.image_rotation(),
         mirror: ImageMirroring::Both,
     }
 }
 
+/// Gap between neighbouring keys, in pixels of the key image, used when splitting wallpapers
+pub const BEZEL_GAP: usize = 24;
+
+/// Returns size of the whole-grid canvas that wallpapers are scaled to
+///
+/// Every key occupies a cell of the largest key size, with [BEZEL_GAP] between the cells
+pub fn get_wallpaper_size(kind: &Kind) -> (usize, usize) {
+    let (cell, _) = get_image_format_for_key(kind, 0).size;
+    let columns = kind.column_count();
+
+    (
+        columns * cell + (columns - 1) * BEZEL_GAP,
+        ROW_COUNT * cell + (ROW_COUNT - 1) * BEZEL_GAP,
+    )
+}
+
+/// Returns (x, y, width, height) of the wallpaper slice for the key, which is a position in the 3x6 matrix
+///
+/// Keys smaller than the cell (narrow corner keys on v2+ devices) take the center of their cell
+pub fn get_wallpaper_tile(kind: &Kind, key: u8) -> (usize, usize, usize, usize) {
+    let (cell, _) = get_image_format_for_key(kind, 0).size;
+    let (width, height) = get_image_format_for_key(kind, key).size;
+
+    let row = key as usize / COL_COUNT;
+    let col = key as usize % COL_COUNT;
+
+    let x = col * (cell + BEZEL_GAP) + (cell - width) / 2;
+    let y = row * (cell + BEZEL_GAP) + (cell - height) / 2;
+
+    (x, y, width, height)
+}
+
 impl Kind {
     /// Matches devices VID+PID pairs to correct kinds
+    ///
+    /// Some PIDs (like `0x1000`) are used by several vendors for different devices,
+    /// so always match by VID first and never by PID alone
+    ///
+    /// Firmware revision is not taken into account: no clone that shares VID and PID
+    /// with a different layout has been reported yet, so there is nothing to match it against
     pub fn from_vid_pid(vid: u16, pid: u16) -> Option<Self> {
         match vid {
             AJAZZ_VID => match pid {
@@ -133,6 +202,7 @@ impl Kind {
             MIRABOX_VID => match pid {
                 AKP153_PID => Some(Kind::AKP153),
                 HSV293S_PID => Some(Kind::HSV293S),
+                HSV293S_CREATOR_PID => Some(Kind::HSV293SCREATOR),
                 _ => None,
             },
 
@@ -176,6 +246,83 @@ impl Kind {
         }
     }
 
+    /// Returns color curves that make panels of the kind look like the source image
+    ///
+    /// No device families are characterized yet, so colors are left as is
+    pub fn color_curves(&self) -> Option<ColorCurves> {
+        None
+    }
+
+    /// Returns number of keys OpenDeck knows about, which is what every per-key structure of the device has to be sized with
+    pub fn key_count(&self) -> usize {
+        self.layout().key_count()
+    }
+
+    /// Returns positions in the 3x6 matrix that have no screen and no switch, but are still reported by the device
+    ///
+    /// Absent keys always make whole columns, so the rest of the keys still make a grid.
+    /// Some 15-key clones lack keys 5, 11 and 17, but none of them is identified yet
+    pub fn absent_keys(&self) -> &'static [u8] {
+        &[]
+    }
+
+    /// Returns keys the device reports and the ones OpenDeck knows about
+    pub fn layout(&self) -> Layout {
+        Layout {
+            matrix: KEY_COUNT,
+            absent: self.absent_keys(),
+        }
+    }
+
+    /// Returns number of columns OpenDeck lays profiles out in
+    pub fn column_count(&self) -> usize {
+        COL_COUNT - self.absent_keys().len() / ROW_COUNT
+    }
+
+    /// Returns positions of the keys device has in the 3x6 matrix
+    pub fn matrix_keys(&self) -> impl Iterator<Item = u8> {
+        self.layout().matrix_keys()
+    }
+
+    /// Converts position in OpenDeck grid to position in the 3x6 matrix
+    pub fn matrix_position(&self, position: u8) -> u8 {
+        self.layout().matrix_position(position)
+    }
+
+    /// Converts position in the 3x6 matrix to position in OpenDeck grid, returns [None] for absent keys
+    pub fn grid_position(&self, key: u8) -> Option<u8> {
+        self.layout().grid_position(key)
+    }
+
+    /// MSD-ONE firmware corrupts image being transferred if a control command arrives in the middle of it
+    pub fn interleaves_commands(&self) -> bool {
+        !matches!(self, Self::MSDONE)
+    }
+
+    /// Rev. 2 AKP153E firmware drops the first image written within ~100ms after brightness change,
+    /// so images are held back for this long after it
+    pub fn brightness_settle_time(&self) -> Duration {
+        match self {
+            Self::AKP153EREV2 => Duration::from_millis(120),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Fresh Ajazz "v1" units keep showing boot logo behind the images until clearing is committed once
+    pub fn shows_boot_logo(&self) -> bool {
+        matches!(self, Self::AKP153 | Self::AKP153E | Self::AKP153R)
+    }
+
+    /// Returns rotation images have to be sent with
+    ///
+    /// TMICE Stream Controller is mounted portrait, so its images are not rotated
+    pub fn image_rotation(&self) -> ImageRotation {
+        match self {
+            Self::TMICESC => ImageRotation::Rot0,
+            _ => ImageRotation::Rot90,
+        }
+    }
+
     /// Returns protocol version for device
     pub fn protocol_version(&self) -> usize {
         match self {
@@ -192,6 +339,7 @@ impl Kind {
     pub fn human_name(&self) -> String {
         match &self {
             Self::HSV293S => "Mirabox HSV293S",
+            Self::HSV293SCREATOR => "Mirabox HSV293S Creator",
             Self::HSV293SV3 => "Mirabox HSV293SV3",
             Self::HSV293SV3_1005 => "Mirabox HSV293SV3",
             Self::AKP153 => "Ajazz AKP153",
@@ -217,6 +365,7 @@ impl Kind {
             Self::AKP153E => "153E",
             Self::AKP153R => "153R",
             Self::HSV293S => "293S",
+            Self::HSV293SCREATOR => "293SC",
             Self::MSDONE => "MSDONE",
             Self::GK150K => "GK150K",
             Self::RMV01 => "RMV01",
@@ -232,9 +381,223 @@ impl Kind {
     }
 }
 
+/// What devices of the kind support, so tools showing them can hide what doesn't apply
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub protocol_version: usize,
+    pub rows: usize,
+    pub columns: usize,
+    pub keys: usize,
+    pub encoders: usize,
+    /// Image sizes of the keys, in OpenDeck order
+    pub key_sizes: Vec<(usize, usize)>,
+    pub wallpaper_size: (usize, usize),
+    /// Whether commands like brightness can be sent in the middle of an image transfer
+    pub interleaves_commands: bool,
+}
+
+impl Kind {
+    /// Returns what devices of the kind support
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            protocol_version: self.protocol_version(),
+            rows: ROW_COUNT,
+            columns: self.column_count(),
+            keys: self.key_count(),
+            encoders: ENCODER_COUNT,
+            key_sizes: self
+                .matrix_keys()
+                .map(|key| get_image_format_for_key(self, key).size)
+                .collect(),
+            wallpaper_size: get_wallpaper_size(self),
+            interleaves_commands: self.interleaves_commands(),
+        }
+    }
+}
+
+/// Keys of the device: the matrix it reports presses in, and positions of the matrix it has no keys at
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub matrix: usize,
+    pub absent: &'static [u8],
+}
+
+impl Layout {
+    /// Returns number of keys OpenDeck knows about
+    pub fn key_count(&self) -> usize {
+        self.matrix - self.absent.len()
+    }
+
+    /// Returns positions of the keys device has in the matrix
+    pub fn matrix_keys(self) -> impl Iterator<Item = u8> {
+        (0..self.matrix as u8).filter(move |key| !self.absent.contains(key))
+    }
+
+    /// Converts position in OpenDeck grid to position in the matrix
+    pub fn matrix_position(&self, position: u8) -> u8 {
+        self.matrix_keys()
+            .nth(position as usize)
+            .unwrap_or(position)
+    }
+
+    /// Converts position in the matrix to position in OpenDeck grid, returns [None] for absent keys
+    pub fn grid_position(&self, key: u8) -> Option<u8> {
+        self.matrix_keys()
+            .position(|present| present == key)
+            .map(|position| position as u8)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CandidateDevice {
     pub id: String,
     pub dev: HidDeviceInfo,
     pub kind: Kind,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(kind: &Kind) -> Vec<(u8, (usize, usize, usize, usize))> {
+        kind.matrix_keys()
+            .map(|key| (key, get_wallpaper_tile(kind, key)))
+            .collect()
+    }
+
+    #[test]
+    fn wallpaper_tiles_fit_the_canvas_without_overlapping() {
+        for kind in [Kind::AKP153, Kind::HSV293SV3] {
+            let (canvas_width, canvas_height) = get_wallpaper_size(&kind);
+            let tiles = tiles(&kind);
+
+            for (key, (x, y, width, height)) in &tiles {
+                assert!(x + width <= canvas_width, "{:?} key {}", kind, key);
+                assert!(y + height <= canvas_height, "{:?} key {}", kind, key);
+                assert_eq!(
+                    (*width, *height),
+                    get_image_format_for_key(&kind, *key).size,
+                    "{:?} key {}",
+                    kind,
+                    key
+                );
+            }
+
+            for (i, (key, a)) in tiles.iter().enumerate() {
+                for (other, b) in &tiles[i + 1..] {
+                    let apart = a.0 + a.2 <= b.0
+                        || b.0 + b.2 <= a.0
+                        || a.1 + a.3 <= b.1
+                        || b.1 + b.3 <= a.1;
+
+                    assert!(apart, "{:?} keys {} and {} overlap", kind, key, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wallpaper_tiles_span_the_canvas() {
+        for kind in [Kind::AKP153, Kind::HSV293SV3] {
+            let (canvas_width, canvas_height) = get_wallpaper_size(&kind);
+
+            // Full-size keys of the first column and the last row touch the canvas edges
+            assert_eq!(get_wallpaper_tile(&kind, 0).0, 0);
+            assert_eq!(get_wallpaper_tile(&kind, 0).1, 0);
+
+            let (_, y, _, height) = get_wallpaper_tile(&kind, 12);
+            assert_eq!(y + height, canvas_height, "{:?}", kind);
+
+            let (x, _, width, _) = get_wallpaper_tile(&kind, 4);
+            let cell = get_image_format_for_key(&kind, 0).size.0;
+            assert_eq!(x + width + BEZEL_GAP + cell, canvas_width, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn narrow_corner_keys_take_the_center_of_their_cell() {
+        let kind = Kind::HSV293SV3;
+
+        for (row, key) in [5, 11, 17].into_iter().enumerate() {
+            let (x, y, width, height) = get_wallpaper_tile(&kind, key);
+
+            assert_eq!((width, height), (82, 82));
+            assert_eq!(x, 5 * (95 + BEZEL_GAP) + (95 - 82) / 2);
+            assert_eq!(y, row * (95 + BEZEL_GAP) + (95 - 82) / 2);
+        }
+    }
+
+    #[test]
+    fn v1_kinds_have_no_narrow_keys() {
+        let kind = Kind::AKP153;
+
+        assert_eq!(
+            get_wallpaper_tile(&kind, 5),
+            (5 * (85 + BEZEL_GAP), 0, 85, 85)
+        );
+        assert_eq!(
+            get_wallpaper_size(&kind),
+            (6 * 85 + 5 * BEZEL_GAP, 3 * 85 + 2 * BEZEL_GAP)
+        );
+    }
+
+    /// 15 keys of a 3x6 matrix without its last column, full 3x6 matrix, and a hypothetical 4x8 one
+    const LAYOUTS: [(Layout, usize); 3] = [
+        (
+            Layout {
+                matrix: 18,
+                absent: &[5, 11, 17],
+            },
+            15,
+        ),
+        (
+            Layout {
+                matrix: 18,
+                absent: &[],
+            },
+            18,
+        ),
+        (
+            Layout {
+                matrix: 32,
+                absent: &[],
+            },
+            32,
+        ),
+    ];
+
+    #[test]
+    fn layouts_count_only_present_keys() {
+        for (layout, keys) in LAYOUTS {
+            assert_eq!(layout.key_count(), keys, "{:?}", layout);
+            assert_eq!(layout.matrix_keys().count(), keys, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn grid_and_matrix_positions_round_trip() {
+        for (layout, keys) in LAYOUTS {
+            for position in 0..keys as u8 {
+                let key = layout.matrix_position(position);
+
+                assert!(!layout.absent.contains(&key), "{:?} {}", layout, position);
+                assert_eq!(layout.grid_position(key), Some(position), "{:?}", layout);
+            }
+
+            for key in layout.absent {
+                assert_eq!(layout.grid_position(*key), None, "{:?}", layout);
+            }
+
+            // Keys outside of the matrix are never mapped to a grid position
+            assert_eq!(layout.grid_position(layout.matrix as u8), None);
+        }
+    }
+
+    #[test]
+    fn supported_kinds_have_full_matrix() {
+        for kind in ALL_KINDS {
+            assert_eq!(kind.key_count(), KEY_COUNT, "{:?}", kind);
+        }
+    }
+}
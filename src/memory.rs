@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{IMAGE_CACHE, encode};
+
+/// Default limit for all the image caches together, in bytes
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_MEMORY_LIMIT);
+
+/// Changes limit for all the image caches together, evicting images if needed
+pub async fn set_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::Relaxed);
+
+    log::info!("Limiting memory of all the image caches to {} bytes", limit);
+
+    enforce().await;
+}
+
+/// Returns sizes of the encoded, decoded and device image caches, in bytes
+pub async fn usage() -> (usize, usize, usize) {
+    let (sources, outputs) = encode::transform_usage().await;
+    let images = IMAGE_CACHE.lock().await.total_usage();
+
+    (outputs, sources, images)
+}
+
+/// Evicts images once caches together go above the limit, even if each of them is within its own limit
+///
+/// Images that are the cheapest to get again go first: encoded images, then decoded ones, and images kept
+/// to restore devices only after that, because losing them means asking OpenDeck for all the images of the device
+pub async fn enforce() {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let (outputs, sources, images) = usage().await;
+
+    if outputs + sources + images <= limit {
+        return;
+    }
+
+    log::info!(
+        "Image caches use {} bytes, which is over {} bytes, evicting",
+        outputs + sources + images,
+        limit
+    );
+
+    let outputs = encode::shrink_outputs(limit.saturating_sub(sources + images)).await;
+    let sources = encode::shrink_sources(limit.saturating_sub(outputs + images)).await;
+
+    IMAGE_CACHE
+        .lock()
+        .await
+        .shrink(limit.saturating_sub(outputs + sources));
+}
+
+/// Logs memory used by all the image caches
+pub async fn log_usage() {
+    let (outputs, sources, images) = usage().await;
+
+    log::debug!(
+        "Image caches use {} of {} bytes: {} encoded, {} decoded, {} to restore devices",
+        outputs + sources + images,
+        LIMIT.load(Ordering::Relaxed),
+        outputs,
+        sources,
+        images
+    );
+}
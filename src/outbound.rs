@@ -0,0 +1,203 @@
+use openaction::{EventHandlerResult, OUTBOUND_EVENT_MANAGER};
+
+use crate::{
+    TRACKER,
+    mappings::{ENCODER_COUNT, Kind, ROW_COUNT},
+};
+
+/// Events plugin sends to OpenDeck, so code sending them can be run without a real connection
+pub trait Outbound {
+    async fn register_device(&self, id: &str, kind: &Kind, name: &str) -> EventHandlerResult;
+    async fn deregister_device(&self, id: &str) -> EventHandlerResult;
+    async fn rerender_images(&self, id: &str) -> EventHandlerResult;
+    async fn key_down(&self, id: &str, key: u8) -> EventHandlerResult;
+    async fn key_up(&self, id: &str, key: u8) -> EventHandlerResult;
+    async fn encoder_down(&self, id: &str, encoder: u8) -> EventHandlerResult;
+    async fn encoder_up(&self, id: &str, encoder: u8) -> EventHandlerResult;
+    async fn encoder_change(&self, id: &str, encoder: u8, ticks: i16) -> EventHandlerResult;
+    /// Writes message to the OpenDeck log
+    async fn notify(&self, message: String) -> EventHandlerResult;
+}
+
+/// Sends events over the global OpenDeck connection, silently dropping them if plugin is not connected yet
+pub struct OpenDeck;
+
+impl Outbound for OpenDeck {
+    async fn register_device(&self, id: &str, kind: &Kind, name: &str) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound
+                .register_device(
+                    id.to_string(),
+                    name.to_string(),
+                    ROW_COUNT as u8,
+                    kind.column_count() as u8,
+                    ENCODER_COUNT as u8,
+                    0,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn deregister_device(&self, id: &str) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.deregister_device(id.to_string()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn rerender_images(&self, id: &str) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.rerender_images(id.to_string()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn key_down(&self, id: &str, key: u8) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.key_down(id.to_string(), key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn key_up(&self, id: &str, key: u8) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.key_up(id.to_string(), key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn encoder_down(&self, id: &str, encoder: u8) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.encoder_down(id.to_string(), encoder).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn encoder_up(&self, id: &str, encoder: u8) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.encoder_up(id.to_string(), encoder).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn encoder_change(&self, id: &str, encoder: u8, ticks: i16) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound
+                .encoder_change(id.to_string(), encoder, ticks)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn notify(&self, message: String) -> EventHandlerResult {
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.log_message(message).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes message to the OpenDeck log from a separate task
+///
+/// Event handlers run while OpenDeck connection is locked for the event, so they can't send anything themselves
+pub async fn notify_later(message: String) {
+    TRACKER.lock().await.spawn(async move {
+        OpenDeck.notify(message).await.ok();
+    });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Records events instead of sending them, so tests can check what OpenDeck would get
+    #[derive(Default)]
+    pub struct Recorder {
+        events: Mutex<Vec<String>>,
+        failures: AtomicUsize,
+    }
+
+    impl Recorder {
+        /// Recorder that fails this many events before it starts accepting them
+        pub fn failing(failures: usize) -> Self {
+            Self {
+                failures: AtomicUsize::new(failures),
+                ..Default::default()
+            }
+        }
+
+        /// Returns recorded events, like `key_down 99-A 3`, in the order they were sent
+        pub fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+
+        fn record(&self, event: String) -> EventHandlerResult {
+            self.events.lock().unwrap().push(event);
+
+            let failed = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |failures| {
+                    failures.checked_sub(1)
+                })
+                .is_ok();
+
+            if failed {
+                return Err(std::io::Error::other("recorder is set to fail").into());
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Outbound for Recorder {
+        async fn register_device(&self, id: &str, kind: &Kind, name: &str) -> EventHandlerResult {
+            self.record(format!("register_device {} {:?} {}", id, kind, name))
+        }
+
+        async fn deregister_device(&self, id: &str) -> EventHandlerResult {
+            self.record(format!("deregister_device {}", id))
+        }
+
+        async fn rerender_images(&self, id: &str) -> EventHandlerResult {
+            self.record(format!("rerender_images {}", id))
+        }
+
+        async fn key_down(&self, id: &str, key: u8) -> EventHandlerResult {
+            self.record(format!("key_down {} {}", id, key))
+        }
+
+        async fn key_up(&self, id: &str, key: u8) -> EventHandlerResult {
+            self.record(format!("key_up {} {}", id, key))
+        }
+
+        async fn encoder_down(&self, id: &str, encoder: u8) -> EventHandlerResult {
+            self.record(format!("encoder_down {} {}", id, encoder))
+        }
+
+        async fn encoder_up(&self, id: &str, encoder: u8) -> EventHandlerResult {
+            self.record(format!("encoder_up {} {}", id, encoder))
+        }
+
+        async fn encoder_change(&self, id: &str, encoder: u8, ticks: i16) -> EventHandlerResult {
+            self.record(format!("encoder_change {} {} {}", id, encoder, ticks))
+        }
+
+        async fn notify(&self, message: String) -> EventHandlerResult {
+            self.record(format!("notify {}", message))
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+/// Name of the plugin's directory inside the platform directories
+const DIR_NAME: &str = "opendeck-akp153";
+
+/// Returns XDG base directory from its variable, or the default inside home if it's unset or relative
+#[cfg(target_os = "linux")]
+fn xdg_dir(
+    var: Option<std::ffi::OsString>,
+    home: Option<std::ffi::OsString>,
+    default: &str,
+) -> Option<PathBuf> {
+    var.map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(PathBuf::from(home?).join(default)))
+}
+
+/// Returns platform directory for plugin state, like `$XDG_STATE_HOME` on Linux
+#[cfg(target_os = "linux")]
+fn platform_state_dir() -> Option<PathBuf> {
+    xdg_dir(
+        std::env::var_os("XDG_STATE_HOME"),
+        std::env::var_os("HOME"),
+        ".local/state",
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn platform_state_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os("HOME")?).join("Library/Application Support"))
+}
+
+#[cfg(target_os = "windows")]
+fn platform_state_dir() -> Option<PathBuf> {
+    std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn platform_state_dir() -> Option<PathBuf> {
+    None
+}
+
+/// Returns platform directory for files that can be recreated at any time, like `$XDG_CACHE_HOME` on Linux
+#[cfg(target_os = "linux")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    xdg_dir(
+        std::env::var_os("XDG_CACHE_HOME"),
+        std::env::var_os("HOME"),
+        ".cache",
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os("HOME")?).join("Library/Caches"))
+}
+
+#[cfg(target_os = "windows")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    // Windows has no separate place for caches, Temp is cleaned up behind the user's back
+    std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn platform_cache_dir() -> Option<PathBuf> {
+    None
+}
+
+/// Creates directory only the current user can access
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    builder.create(dir)
+}
+
+/// Returns plugin's directory inside the platform one, falling back to the plugin directory if it can't be used
+fn plugin_dir(platform: Option<PathBuf>) -> PathBuf {
+    let Some(dir) = platform.map(|dir| dir.join(DIR_NAME)) else {
+        return PathBuf::from(".");
+    };
+
+    match create_dir(&dir) {
+        Ok(()) => dir,
+        Err(err) => {
+            log::warn!(
+                "Unable to create {}, keeping files in the plugin directory: {}",
+                dir.display(),
+                err
+            );
+
+            PathBuf::from(".")
+        }
+    }
+}
+
+/// Directory all the plugin state is kept in
+static STATE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    // Tests keep their state away from the one of the installed plugin
+    if cfg!(test) {
+        let dir = std::env::temp_dir().join(format!("state-{}", std::process::id()));
+        return plugin_dir(Some(dir));
+    }
+
+    plugin_dir(platform_state_dir())
+});
+
+/// Directory for the files plugin writes out, but never reads back
+static CACHE_DIR: LazyLock<PathBuf> = LazyLock::new(|| plugin_dir(platform_cache_dir()));
+
+/// Returns path of the state file, moving it from the plugin directory where it was kept before
+pub fn state_file(name: &str) -> PathBuf {
+    file_in(&STATE_DIR, Path::new("."), name)
+}
+
+/// Returns path of the file that can be recreated at any time, moving it from the plugin directory as well
+pub fn cache_file(name: &str) -> PathBuf {
+    file_in(&CACHE_DIR, Path::new("."), name)
+}
+
+/// Returns path of the file in the directory, moving it there once from the old directory
+fn file_in(dir: &Path, old_dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    let old = old_dir.join(name);
+
+    if dir != old_dir && !path.exists() && old.exists() {
+        log::info!("Moving {} to {}", old.display(), path.display());
+
+        // Rename fails across file systems, so copy the file there
+        let moved = std::fs::rename(&old, &path).or_else(|_| {
+            std::fs::copy(&old, &path)?;
+            std::fs::remove_file(&old)
+        });
+
+        if let Err(err) = moved {
+            log::warn!("Unable to move {}, using it as is: {}", old.display(), err);
+            return old;
+        }
+    }
+
+    path
+}
+
+/// Returns directory for files that only live while the user is logged in, shared by all the plugin processes
+pub fn runtime_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns empty directory for the test, removing whatever the previous run left there
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "opendeck-akp153-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn file_is_moved_from_old_directory_once() {
+        let base = temp_dir("migrate");
+        let (dir, old_dir) = (base.join("state"), base.join("plugin"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&old_dir).unwrap();
+        std::fs::write(old_dir.join("stats.json"), "{}").unwrap();
+
+        let path = file_in(&dir, &old_dir, "stats.json");
+
+        assert_eq!(path, dir.join("stats.json"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+        assert!(!old_dir.join("stats.json").exists());
+
+        // File written to the old directory later, e.g. by an older version, doesn't replace the moved one
+        std::fs::write(old_dir.join("stats.json"), "old").unwrap();
+
+        assert_eq!(file_in(&dir, &old_dir, "stats.json"), path);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+    }
+
+    #[test]
+    fn missing_file_is_created_in_new_directory() {
+        let base = temp_dir("fresh");
+
+        let path = file_in(&base.join("state"), &base.join("plugin"), "devices.json");
+
+        assert_eq!(path, base.join("state/devices.json"));
+    }
+
+    #[test]
+    fn old_file_is_used_if_it_cant_be_moved() {
+        let base = temp_dir("unmovable");
+        std::fs::write(base.join("plugin.log"), "log").unwrap();
+
+        // Directory was never created, so neither rename nor copy can succeed
+        let path = file_in(&base.join("missing"), &base, "plugin.log");
+
+        assert_eq!(path, base.join("plugin.log"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "log");
+    }
+
+    #[test]
+    fn plugin_directory_is_used_if_platform_one_cant_be_created() {
+        let base = temp_dir("blocked");
+        std::fs::write(base.join("file"), "").unwrap();
+
+        assert_eq!(plugin_dir(Some(base.join("file"))), PathBuf::from("."));
+        assert_eq!(plugin_dir(None), PathBuf::from("."));
+    }
+
+    #[test]
+    fn plugin_directory_is_created_inside_platform_one() {
+        let base = temp_dir("created");
+
+        let dir = plugin_dir(Some(base.clone()));
+
+        assert_eq!(dir, base.join(DIR_NAME));
+        assert!(dir.is_dir());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xdg_dir_falls_back_to_home_if_unset_or_relative() {
+        let home = Some("/home/user".into());
+
+        assert_eq!(
+            xdg_dir(Some("/tmp/cache".into()), home.clone(), ".cache"),
+            Some(PathBuf::from("/tmp/cache"))
+        );
+        assert_eq!(
+            xdg_dir(Some("cache".into()), home.clone(), ".cache"),
+            Some(PathBuf::from("/home/user/.cache"))
+        );
+        assert_eq!(
+            xdg_dir(None, home, ".local/state"),
+            Some(PathBuf::from("/home/user/.local/state"))
+        );
+        assert_eq!(xdg_dir(None, None, ".cache"), None);
+    }
+}
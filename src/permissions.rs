@@ -0,0 +1,114 @@
+use mirajazz::{error::MirajazzError, types::HidDeviceInfo};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(target_os = "linux")]
+use async_hid::DeviceId;
+#[cfg(target_os = "linux")]
+use std::{os::unix::fs::MetadataExt, path::Path, time::Duration};
+
+/// Uptime in seconds below which denied access is likely udev not having applied permissions yet
+#[cfg(target_os = "linux")]
+const EARLY_BOOT: f64 = 300.0;
+
+/// How often permissions of the device node are checked for changes
+#[cfg(target_os = "linux")]
+const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long permissions are waited for before giving up on the device
+#[cfg(target_os = "linux")]
+const WAIT_LIMIT: Duration = Duration::from_secs(60);
+
+/// Checks if device couldn't be opened because plugin started before udev applied permissions at boot
+#[cfg(target_os = "linux")]
+pub fn denied_at_boot(err: &MirajazzError) -> bool {
+    let MirajazzError::HidError(async_hid::HidError::Other(err)) = err else {
+        return false;
+    };
+
+    let denied = err
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::PermissionDenied);
+
+    denied && uptime().is_some_and(|uptime| uptime < EARLY_BOOT)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn denied_at_boot(_err: &MirajazzError) -> bool {
+    // Only udev applies permissions after devices appear
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn uptime() -> Option<f64> {
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+
+    uptime.split_whitespace().next()?.parse().ok()
+}
+
+/// Asks udev to process the device again, then waits for permissions of its node to change,
+/// returning false if they didn't
+///
+/// On some systems the `uaccess` tag is only applied on the next device event, so without one
+/// device stays inaccessible until it's replugged
+#[cfg(target_os = "linux")]
+pub async fn wait_for_change(dev: &HidDeviceInfo, token: &CancellationToken) -> bool {
+    #[allow(irrefutable_let_patterns)]
+    let DeviceId::DevPath(path) = &dev.id else {
+        return false;
+    };
+
+    let Some(changed) = changed_at(path) else {
+        return false;
+    };
+
+    retrigger(path);
+
+    let started = tokio::time::Instant::now();
+
+    while started.elapsed() < WAIT_LIMIT {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {},
+            _ = token.cancelled() => return false
+        };
+
+        if changed_at(path).is_some_and(|at| at != changed) {
+            log::info!("Permissions of {} changed", path.display());
+
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn wait_for_change(_dev: &HidDeviceInfo, _token: &CancellationToken) -> bool {
+    false
+}
+
+/// Returns when owner, mode or ACL of the node last changed
+#[cfg(target_os = "linux")]
+fn changed_at(path: &Path) -> Option<(i64, i64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+
+    Some((metadata.ctime(), metadata.ctime_nsec()))
+}
+
+/// Sends `change` event for the node, same as `udevadm trigger` does, which only works with enough permissions
+#[cfg(target_os = "linux")]
+fn retrigger(path: &Path) {
+    let Some(name) = path.file_name() else {
+        return;
+    };
+
+    let uevent = Path::new("/sys/class/hidraw").join(name).join("uevent");
+
+    match std::fs::write(&uevent, "change") {
+        Ok(()) => log::info!("Asked udev to process {} again", path.display()),
+        Err(err) => log::info!(
+            "Unable to ask udev to process {} again, waiting for it anyway: {}",
+            path.display(),
+            err
+        ),
+    }
+}
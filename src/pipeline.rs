@@ -0,0 +1,660 @@
+use image::{
+    ColorType, DynamicImage, ImageError,
+    codecs::{bmp::BmpEncoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    mem::Discriminant,
+    sync::{LazyLock, Mutex},
+};
+
+use serde::Deserialize;
+
+use mirajazz::types::{ImageFormat, ImageMirroring, ImageMode, ImageRotation};
+
+// Images are converted into the data device expects in stages, which are always applied in this order:
+//
+// 1. Zoom in or out around the center
+// 2. Resize to the key size, stretching or cropping the image
+// 3. Adjust for accessibility (invert, boost contrast)
+// 4. Correct colors and dim
+// 5. Rotate
+// 6. Mirror
+// 7. Encode
+//
+// Every stage is a separate function, so it can be reused and checked on its own
+
+/// Quality used unless device settings specify another one
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// Per-channel lookup tables, mapping every possible channel value to a corrected one
+#[derive(Debug, Clone, PartialEq, Hash, Deserialize)]
+pub struct ColorCurves {
+    pub red: Vec<u8>,
+    pub green: Vec<u8>,
+    pub blue: Vec<u8>,
+}
+
+impl ColorCurves {
+    /// Checks that every table covers all 256 channel values
+    pub fn is_valid(&self) -> bool {
+        [&self.red, &self.green, &self.blue]
+            .iter()
+            .all(|table| table.len() == 256)
+    }
+}
+
+/// Point of the image that stays in view when it's cropped to fill the key, from 0.0 to 1.0 on each axis
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CropAnchor {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for CropAnchor {
+    fn default() -> Self {
+        Self { x: 0.5, y: 0.5 }
+    }
+}
+
+/// Options for the JPEG encoder
+///
+/// Chroma is never subsampled (always 4:4:4), because that's the only mode the encoder supports,
+/// so quality is the only thing to trade bandwidth for cleaner edges with
+#[derive(Debug, Clone, Hash)]
+pub struct JpegOptions {
+    pub quality: u8,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        Self {
+            quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+/// Options for the pipeline, specific to the device the image is for
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Zooms image in when above 1.0, cutting its edges off, or out when below, surrounding it with black
+    pub zoom: Option<f32>,
+    /// Crops image to fill the key instead of stretching it
+    pub anchor: Option<CropAnchor>,
+    pub invert: bool,
+    pub boost_contrast: bool,
+    pub curves: Option<ColorCurves>,
+    /// Brightness of the key image in percent, to make some keys darker than others
+    pub dim: Option<u8>,
+    /// Rotation to use instead of the one from the format, for devices mounted differently
+    pub rotation: Option<ImageRotation>,
+    pub jpeg: JpegOptions,
+}
+
+/// Images encoded with the same options are reused, so options are hashed with floats taken by their bits
+impl Hash for Options {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let Self {
+            zoom,
+            anchor,
+            invert,
+            boost_contrast,
+            curves,
+            dim,
+            rotation,
+            jpeg,
+        } = self;
+
+        zoom.map(f32::to_bits).hash(state);
+        anchor
+            .map(|anchor| (anchor.x.to_bits(), anchor.y.to_bits()))
+            .hash(state);
+        invert.hash(state);
+        boost_contrast.hash(state);
+        curves.hash(state);
+        dim.hash(state);
+        rotation.hash(state);
+        jpeg.hash(state);
+    }
+}
+
+impl Options {
+    /// Checks if options leave pixels as is
+    pub fn keeps_pixels(&self) -> bool {
+        self.zoom.is_none()
+            && self.anchor.is_none()
+            && !self.invert
+            && !self.boost_contrast
+            && self.curves.is_none()
+            && self.dim.is_none()
+    }
+}
+
+/// How much contrast is boosted, as a multiplier of distance from the middle gray
+const CONTRAST_BOOST: f32 = 1.5;
+
+/// Lookup table for contrast boost, so every pixel costs only a table lookup
+static CONTRAST_TABLE: LazyLock<[u8; 256]> = LazyLock::new(|| {
+    std::array::from_fn(|value| {
+        ((value as f32 - 128.0) * CONTRAST_BOOST + 128.0).clamp(0.0, 255.0) as u8
+    })
+});
+
+/// Zooms image around its center, zooming in crops it and zooming out keeps its size, resize stage scales it to the key
+pub fn zoom(options: &Options, image: DynamicImage) -> DynamicImage {
+    let Some(zoom) = options.zoom else {
+        return image;
+    };
+
+    let (width, height) = (image.width(), image.height());
+    let zoomed_width = ((width as f32 / zoom).round() as u32).max(1);
+    let zoomed_height = ((height as f32 / zoom).round() as u32).max(1);
+
+    // Zooming in crops the middle of the image, zooming out shrinks the image into the middle of a black one
+    if zoomed_width <= width && zoomed_height <= height {
+        return image.crop_imm(
+            (width - zoomed_width) / 2,
+            (height - zoomed_height) / 2,
+            zoomed_width,
+            zoomed_height,
+        );
+    }
+
+    let shrunk = image.resize_exact(
+        width * width / zoomed_width,
+        height * height / zoomed_height,
+        FilterType::Triangle,
+    );
+    let mut canvas = DynamicImage::new_rgb8(width, height);
+
+    image::imageops::overlay(
+        &mut canvas,
+        &shrunk,
+        ((width - shrunk.width()) / 2) as i64,
+        ((height - shrunk.height()) / 2) as i64,
+    );
+
+    canvas
+}
+
+/// Resizes image to the key size, stretching it, or cropping it around the anchor if there is one
+pub fn resize(format: &ImageFormat, options: &Options, image: DynamicImage) -> DynamicImage {
+    let (width, height) = (format.size.0 as u32, format.size.1 as u32);
+
+    let Some(anchor) = options.anchor else {
+        return image.resize_exact(width, height, FilterType::Nearest);
+    };
+
+    let scale = (width as f32 / image.width() as f32).max(height as f32 / image.height() as f32);
+    let scaled_width = ((image.width() as f32 * scale).ceil() as u32).max(width);
+    let scaled_height = ((image.height() as f32 * scale).ceil() as u32).max(height);
+
+    let image = image.resize_exact(scaled_width, scaled_height, FilterType::Nearest);
+
+    let x = ((scaled_width - width) as f32 * anchor.x.clamp(0.0, 1.0)).round() as u32;
+    let y = ((scaled_height - height) as f32 * anchor.y.clamp(0.0, 1.0)).round() as u32;
+
+    image.crop_imm(x, y, width, height)
+}
+
+/// Inverts colors and boosts contrast, making icons easier to see on glossy panels
+pub fn adjust(options: &Options, image: DynamicImage) -> DynamicImage {
+    if !options.invert && !options.boost_contrast {
+        return image;
+    }
+
+    let mut image = image.into_rgb8();
+
+    // Channels are processed as a flat slice, so the loops can be vectorized
+    if options.invert {
+        for value in image.iter_mut() {
+            *value = 255 - *value;
+        }
+    }
+
+    if options.boost_contrast {
+        for value in image.iter_mut() {
+            *value = CONTRAST_TABLE[*value as usize];
+        }
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Corrects colors with the curves, so panels of different families look the same, and dims the image
+///
+/// Dimming is folded into the curves, so it costs nothing on top of the correction
+pub fn correct_colors(options: &Options, image: DynamicImage) -> DynamicImage {
+    if options.curves.is_none() && options.dim.is_none() {
+        return image;
+    }
+
+    let dim = options.dim.unwrap_or(100).min(100) as u32;
+    let table = |curve: Option<&Vec<u8>>| -> [u8; 256] {
+        std::array::from_fn(|value| {
+            let value = curve.map(|curve| curve[value]).unwrap_or(value as u8);
+
+            (value as u32 * dim / 100) as u8
+        })
+    };
+
+    let curves = options.curves.as_ref();
+    let red = table(curves.map(|curves| &curves.red));
+    let green = table(curves.map(|curves| &curves.green));
+    let blue = table(curves.map(|curves| &curves.blue));
+
+    let mut image = image.into_rgb8();
+
+    for pixel in image.pixels_mut() {
+        pixel.0 = [
+            red[pixel.0[0] as usize],
+            green[pixel.0[1] as usize],
+            blue[pixel.0[2] as usize],
+        ];
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Rotates image the way device expects it
+pub fn rotate(format: &ImageFormat, options: &Options, image: DynamicImage) -> DynamicImage {
+    match options.rotation.unwrap_or(format.rotation) {
+        ImageRotation::Rot0 => image,
+        ImageRotation::Rot90 => image.rotate90(),
+        ImageRotation::Rot180 => image.rotate180(),
+        ImageRotation::Rot270 => image.rotate270(),
+    }
+}
+
+/// Mirrors image the way device expects it
+pub fn mirror(format: &ImageFormat, image: DynamicImage) -> DynamicImage {
+    match format.mirror {
+        ImageMirroring::None => image,
+        ImageMirroring::X => image.fliph(),
+        ImageMirroring::Y => image.flipv(),
+        ImageMirroring::Both => image.fliph().flipv(),
+    }
+}
+
+thread_local! {
+    /// Size of the image encoded last on this thread
+    static ENCODED_SIZE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Encodes image into the format device expects
+pub fn encode(
+    format: &ImageFormat,
+    jpeg: &JpegOptions,
+    image: DynamicImage,
+) -> Result<Vec<u8>, ImageError> {
+    let (width, height) = (image.width(), image.height());
+    let data = image.into_rgb8();
+
+    // Keys of a device are mostly encoded into images of similar size, so the buffer is allocated once
+    // with the size of the previous image, instead of growing it step by step
+    let mut buf = Vec::with_capacity(ENCODED_SIZE.get());
+
+    match format.mode {
+        ImageMode::None => {}
+        ImageMode::BMP => {
+            BmpEncoder::new(&mut buf).encode(&data, width, height, ColorType::Rgb8.into())?
+        }
+        ImageMode::JPEG => JpegEncoder::new_with_quality(&mut buf, jpeg.quality).encode(
+            &data,
+            width,
+            height,
+            ColorType::Rgb8.into(),
+        )?,
+    }
+
+    ENCODED_SIZE.set(buf.len());
+
+    Ok(buf)
+}
+
+/// Blends two images of the same size, `amount` of 0.0 gives the first one and 1.0 gives the second one
+///
+/// Images of different sizes can't be blended, so the second one is returned as is
+pub fn blend(from: &DynamicImage, to: &DynamicImage, amount: f32) -> DynamicImage {
+    if from.width() != to.width() || from.height() != to.height() {
+        return to.clone();
+    }
+
+    let amount = amount.clamp(0.0, 1.0);
+    let mut image = from.to_rgb8();
+    let to = to.to_rgb8();
+
+    for (value, target) in image.iter_mut().zip(to.iter()) {
+        *value = (*value as f32 + (*target as f32 - *value as f32) * amount).round() as u8;
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Returns size of a baseline JPEG image, or [None] if data is not one
+pub fn jpeg_size(data: &[u8]) -> Option<(usize, usize)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut offset = 2;
+
+    loop {
+        let [0xFF, marker] = *data.get(offset..offset + 2)? else {
+            return None;
+        };
+
+        match marker {
+            // Baseline frame header: length, precision, height, width
+            0xC0 => {
+                let header = data.get(offset + 4..offset + 9)?;
+
+                let height = u16::from_be_bytes([header[1], header[2]]) as usize;
+                let width = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+                return Some((width, height));
+            }
+            // Progressive and other frame types, or scan data started without any frame header
+            0xC1..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xDA => return None,
+            _ => {
+                let length = data.get(offset + 2..offset + 4)?;
+
+                offset += 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+            }
+        }
+    }
+}
+
+/// Returns size of a BMP image, or [None] if data is not one
+pub fn bmp_size(data: &[u8]) -> Option<(usize, usize)> {
+    if !data.starts_with(b"BM") {
+        return None;
+    }
+
+    // Info header follows the 14 bytes file header, width and height are signed, top-down images have negative height
+    let width = i32::from_le_bytes(data.get(18..22)?.try_into().ok()?);
+    let height = i32::from_le_bytes(data.get(22..26)?.try_into().ok()?);
+
+    Some((
+        width.unsigned_abs() as usize,
+        height.unsigned_abs() as usize,
+    ))
+}
+
+/// Checks if encoded image is of the size and format the key expects
+pub fn fits(format: &ImageFormat, data: &[u8]) -> bool {
+    match format.mode {
+        ImageMode::None => true,
+        ImageMode::BMP => bmp_size(data) == Some(format.size),
+        ImageMode::JPEG => jpeg_size(data) == Some(format.size),
+    }
+}
+
+/// Encoded black frames by image format and key size
+type BlankFrames = HashMap<(Discriminant<ImageMode>, (usize, usize)), Vec<u8>>;
+
+/// Returns encoded black frame for the key, frames are encoded once per format and size and reused
+pub fn blank(format: &ImageFormat) -> Result<Vec<u8>, ImageError> {
+    static FRAMES: LazyLock<Mutex<BlankFrames>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+    let mut frames = FRAMES.lock().unwrap();
+    let key = (std::mem::discriminant(&format.mode), format.size);
+
+    if let Some(frame) = frames.get(&key) {
+        return Ok(frame.clone());
+    }
+
+    let (width, height) = format.size;
+    let frame = encode(
+        format,
+        &JpegOptions::default(),
+        DynamicImage::new_rgb8(width as u32, height as u32),
+    )?;
+
+    frames.insert(key, frame.clone());
+
+    Ok(frame)
+}
+
+/// Runs image through all the stages
+pub fn process(
+    format: &ImageFormat,
+    options: &Options,
+    image: DynamicImage,
+) -> Result<Vec<u8>, ImageError> {
+    let image = zoom(options, image);
+    let image = resize(format, options, image);
+    let image = adjust(options, image);
+    let image = correct_colors(options, image);
+    let image = rotate(format, options, image);
+    let image = mirror(format, image);
+
+    encode(format, &options.jpeg, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mappings::{Kind, get_image_format_for_key},
+        settings::Settings,
+    };
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    fn format(mode: ImageMode, size: (usize, usize)) -> ImageFormat {
+        ImageFormat {
+            mode,
+            size,
+            rotation: ImageRotation::Rot0,
+            mirror: ImageMirroring::None,
+        }
+    }
+
+    #[test]
+    fn encoded_images_fit_only_keys_of_their_size() {
+        for mode in [ImageMode::JPEG, ImageMode::BMP] {
+            let data = blank(&format(mode, (85, 85))).unwrap();
+
+            assert!(fits(&format(mode, (85, 85)), &data));
+            assert!(!fits(&format(mode, (95, 95)), &data));
+        }
+    }
+
+    #[test]
+    fn image_of_another_format_does_not_fit() {
+        let jpeg = encode(
+            &format(ImageMode::JPEG, (85, 85)),
+            &JpegOptions::default(),
+            DynamicImage::new_rgb8(85, 85),
+        )
+        .unwrap();
+
+        assert!(!fits(&format(ImageMode::BMP, (85, 85)), &jpeg));
+    }
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    /// Returns color of the pixel, ignoring alpha
+    fn color(image: &DynamicImage, x: u32, y: u32) -> Rgb<u8> {
+        let [r, g, b, _] = image.get_pixel(x, y).0;
+        Rgb([r, g, b])
+    }
+
+    #[test]
+    fn opposite_anchors_crop_opposite_edges() {
+        // Wide image, red on the left and blue on the right, cropped to a square key
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(200, 100, |x, _| {
+            if x < 100 { RED } else { BLUE }
+        }));
+        let format = format(ImageMode::JPEG, (85, 85));
+
+        let crop = |x: f32, y: f32| {
+            let options = Options {
+                anchor: Some(CropAnchor { x, y }),
+                ..Default::default()
+            };
+
+            resize(&format, &options, image.clone())
+        };
+
+        let left = crop(0.0, 0.0);
+        let right = crop(1.0, 1.0);
+
+        assert_eq!((left.width(), left.height()), (85, 85));
+        assert_eq!((right.width(), right.height()), (85, 85));
+        assert!((0..85).all(|x| color(&left, x, 42) == RED));
+        assert!((0..85).all(|x| color(&right, x, 42) == BLUE));
+    }
+
+    #[test]
+    fn zooming_in_twice_shows_center_quarter() {
+        // Image split into four colored quadrants, with the center quarter touching all of them
+        let quadrants = [
+            [Rgb([255, 0, 0]), Rgb([0, 255, 0])],
+            [Rgb([0, 0, 255]), Rgb([255, 255, 0])],
+        ];
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 100, |x, y| {
+            quadrants[(y / 50) as usize][(x / 50) as usize]
+        }));
+        let options = Options {
+            zoom: Some(2.0),
+            ..Default::default()
+        };
+
+        let zoomed = zoom(&options, image.clone());
+
+        assert_eq!((zoomed.width(), zoomed.height()), (50, 50));
+
+        for y in 0..50 {
+            for x in 0..50 {
+                assert_eq!(color(&zoomed, x, y), color(&image, x + 25, y + 25));
+            }
+        }
+
+        assert_eq!(color(&zoomed, 0, 0), quadrants[0][0]);
+        assert_eq!(color(&zoomed, 49, 0), quadrants[0][1]);
+        assert_eq!(color(&zoomed, 0, 49), quadrants[1][0]);
+        assert_eq!(color(&zoomed, 49, 49), quadrants[1][1]);
+    }
+
+    /// Image split into red, green, blue and white quadrants, from the top left clockwise
+    fn quadrants(size: u32) -> DynamicImage {
+        let half = size / 2;
+
+        DynamicImage::ImageRgb8(RgbImage::from_fn(size, size, |x, y| {
+            match (x < half, y < half) {
+                (true, true) => Rgb([255, 0, 0]),
+                (false, true) => Rgb([0, 255, 0]),
+                (false, false) => Rgb([0, 0, 255]),
+                (true, false) => Rgb([255, 255, 255]),
+            }
+        }))
+    }
+
+    /// FNV-1a hash, which unlike the std hasher is the same on every build
+    ///
+    /// Snapshots change with the JPEG encoder, layout of the decoded image is checked next to them,
+    /// so that a new encoder version only needs the snapshots recorded again
+    fn fnv(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// Checks that decoded color is the expected one, allowing for JPEG artifacts
+    fn assert_close(actual: Rgb<u8>, expected: [u8; 3]) {
+        let close = actual
+            .0
+            .iter()
+            .zip(expected)
+            .all(|(actual, expected)| actual.abs_diff(expected) <= 8);
+
+        assert!(close, "{:?} is not close to {:?}", actual, expected);
+    }
+
+    /// Runs the quadrants through the whole pipeline for the first key of the kind
+    fn snapshot(kind: &Kind, options: &Options) -> (Vec<u8>, DynamicImage) {
+        let format = get_image_format_for_key(kind, 0);
+        let data = process(&format, options, quadrants(120)).unwrap();
+        let decoded = image::load_from_memory(&data).unwrap();
+
+        assert_eq!(
+            (decoded.width() as usize, decoded.height() as usize),
+            format.size
+        );
+
+        (data, decoded)
+    }
+
+    /// Checks quadrants of the source image, from the top left clockwise, after rotation by 90 degrees
+    /// and mirroring by both axes, which turn the image by 270 degrees in total
+    fn assert_layout(decoded: &DynamicImage, quadrants: [[u8; 3]; 4]) {
+        let (near_x, near_y) = (decoded.width() / 4, decoded.height() / 4);
+        let (far_x, far_y) = (near_x * 3, near_y * 3);
+
+        assert_close(color(decoded, near_x, far_y), quadrants[0]);
+        assert_close(color(decoded, near_x, near_y), quadrants[1]);
+        assert_close(color(decoded, far_x, near_y), quadrants[2]);
+        assert_close(color(decoded, far_x, far_y), quadrants[3]);
+    }
+
+    const SOURCE: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+    #[test]
+    fn v1_kind_snapshot() {
+        let (data, decoded) = snapshot(&Kind::AKP153, &Options::default());
+
+        assert_layout(&decoded, SOURCE);
+        assert_eq!(fnv(&data), 13653562747688454180);
+    }
+
+    #[test]
+    fn v2_kind_snapshot() {
+        let (data, decoded) = snapshot(&Kind::HSV293SV3, &Options::default());
+
+        assert_layout(&decoded, SOURCE);
+        assert_eq!(fnv(&data), 8156232372338351816);
+    }
+
+    #[test]
+    fn colors_are_inverted_before_dimming() {
+        let options = Options {
+            invert: true,
+            dim: Some(50),
+            ..Default::default()
+        };
+
+        // Dimming before inverting would make red (128, 255, 255) instead
+        let (_, decoded) = snapshot(&Kind::HSV293SV3, &options);
+
+        assert_layout(
+            &decoded,
+            [[0, 127, 127], [127, 0, 127], [127, 127, 0], [0, 0, 0]],
+        );
+    }
+
+    #[test]
+    fn tmicesc_images_are_rotated_only_when_overridden() {
+        // Mirroring by both axes alone turns the image by 180 degrees
+        let (_, decoded) = snapshot(&Kind::TMICESC, &Options::default());
+        assert_layout(&decoded, [SOURCE[1], SOURCE[2], SOURCE[3], SOURCE[0]]);
+
+        let mut settings = Settings::default();
+        settings.image_rotation.insert("214-A".to_string(), 90);
+        settings.image_rotation.insert("214-B".to_string(), 45);
+
+        assert!(settings.rotation_override("214-B").is_none());
+
+        let options = Options {
+            rotation: settings.rotation_override("214-A"),
+            ..Default::default()
+        };
+        let (_, decoded) = snapshot(&Kind::TMICESC, &options);
+        assert_layout(&decoded, SOURCE);
+    }
+}
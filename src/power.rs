@@ -0,0 +1,47 @@
+use mirajazz::{error::MirajazzError, types::HidDeviceInfo};
+
+#[cfg(target_os = "linux")]
+use crate::sysfs::find_usb_device;
+
+// Errno values that hidraw returns when the device failed to come back from autosuspend
+#[cfg(target_os = "linux")]
+const RESUME_ERRNOS: [i32; 3] = [
+    32,  // EPIPE
+    71,  // EPROTO
+    110, // ETIMEDOUT
+];
+
+/// Keeps the USB device powered on, so it won't be autosuspended by the kernel
+#[cfg(target_os = "linux")]
+pub fn disable_autosuspend(dev: &HidDeviceInfo) -> Result<(), std::io::Error> {
+    let path = find_usb_device(dev)
+        .ok_or(std::io::ErrorKind::NotFound)?
+        .join("power/control");
+
+    log::info!("Writing \"on\" to {}", path.display());
+
+    std::fs::write(path, "on")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn disable_autosuspend(_dev: &HidDeviceInfo) -> Result<(), std::io::Error> {
+    // USB autosuspend is only a problem on Linux
+    Ok(())
+}
+
+/// Checks if error looks like the device failed to resume from autosuspend
+#[cfg(target_os = "linux")]
+pub fn is_resume_error(err: &MirajazzError) -> bool {
+    let MirajazzError::HidError(async_hid::HidError::Other(err)) = err else {
+        return false;
+    };
+
+    err.downcast_ref::<std::io::Error>()
+        .and_then(|err| err.raw_os_error())
+        .is_some_and(|errno| RESUME_ERRNOS.contains(&errno))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_resume_error(_err: &MirajazzError) -> bool {
+    false
+}
@@ -0,0 +1,106 @@
+use mirajazz::device::Device;
+use std::{collections::HashMap, sync::LazyLock};
+use tokio::sync::Mutex;
+
+use crate::{
+    DEVICES, IMAGE_CACHE, SETTINGS, TRACKER,
+    device::{device_kind, write_key},
+    encode::pressed_variant,
+    inputs::opendeck_to_device,
+    mappings::get_image_format_for_key,
+};
+
+/// How bright image of the held key is, in percent
+const PRESSED_DIM: u8 = 70;
+
+/// Keys held down by device id and device key, with whether OpenDeck replaced their image during the press
+static HELD: LazyLock<Mutex<HashMap<(String, u8), bool>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Shows darker version of the key image while the key is held, `position` is the key position in the 3x6 matrix
+///
+/// Key is marked as held right away, but the image is darkened and written in the background,
+/// so reading the device is not held up by it
+pub async fn key_down(id: &str, position: u8) {
+    if !SETTINGS.read().await.pressed_effect.contains(id) {
+        return;
+    }
+
+    HELD.lock()
+        .await
+        .insert((id.to_string(), opendeck_to_device(position)), false);
+
+    let id = id.to_string();
+
+    TRACKER.lock().await.spawn(async move {
+        let devices = DEVICES.read().await;
+
+        if let Some(device) = devices.get(&id) {
+            show_pressed(&id, device, position).await;
+        }
+    });
+}
+
+async fn show_pressed(id: &str, device: &Device, position: u8) {
+    let key = opendeck_to_device(position);
+
+    let Some(data) = IMAGE_CACHE.lock().await.get(id, key) else {
+        return;
+    };
+
+    let format = get_image_format_for_key(&device_kind(id, device).await, position);
+
+    let variant = match pressed_variant(format, data, PRESSED_DIM).await {
+        Ok(variant) => variant,
+        Err(err) => {
+            log::warn!("Unable to darken image of key {} of {}: {}", key, id, err);
+            return;
+        }
+    };
+
+    // Image from OpenDeck, like the one of the next state of the action, wins over the effect
+    if !is_held(id, key).await {
+        return;
+    }
+
+    write_key(id, device, key, &variant).await.ok();
+
+    // Key could be released, or get a new image, while the effect was written, so put the image back over it
+    if !is_held(id, key).await {
+        let data = IMAGE_CACHE.lock().await.get(id, key);
+
+        if let Some(data) = data {
+            write_key(id, device, key, &data).await.ok();
+        }
+    }
+}
+
+/// Checks if the key is still held, and OpenDeck didn't replace its image during the press
+async fn is_held(id: &str, key: u8) -> bool {
+    HELD.lock().await.get(&(id.to_string(), key)) == Some(&false)
+}
+
+/// Brings key image back once the key is released
+pub async fn key_up(id: &str, device: &Device, position: u8) {
+    let key = opendeck_to_device(position);
+
+    // Nothing to bring back if OpenDeck already replaced the image
+    if HELD.lock().await.remove(&(id.to_string(), key)) != Some(false) {
+        return;
+    }
+
+    let Some(data) = IMAGE_CACHE.lock().await.get(id, key) else {
+        return;
+    };
+
+    write_key(id, device, key, &data).await.ok();
+}
+
+/// Remembers that OpenDeck replaced image of the held key, or of all the keys if there is no key
+pub async fn image_replaced(id: &str, key: Option<u8>) {
+    for ((held_id, held_key), replaced) in HELD.lock().await.iter_mut() {
+        if held_id == id && key.is_none_or(|key| key == *held_key) {
+            *replaced = true;
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// How many repeated lines are let through before they are limited
+pub const BURST: u32 = 5;
+
+/// How often a repeated line is let through once it's limited
+pub const WINDOW: Duration = Duration::from_secs(30);
+
+struct Entry {
+    /// Lines let through since the entry was last quiet for a whole window
+    allowed: u32,
+    last_allowed: Instant,
+    suppressed: u64,
+}
+
+/// Lets the first few repeated log lines through, then one per window, counting the ones held back
+///
+/// Device that half-died can fail the same way hundreds of times per second, which would bury the first failure
+pub struct RateLimiter<K> {
+    burst: u32,
+    window: Duration,
+    entries: HashMap<K, Entry>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(burst: u32, window: Duration) -> Self {
+        Self {
+            burst,
+            window,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns [None] if the line has to be held back, or number of lines held back since the last one let through
+    pub fn check(&mut self, key: K) -> Option<u64> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: K, now: Instant) -> Option<u64> {
+        let entry = self.entries.entry(key).or_insert(Entry {
+            allowed: 0,
+            last_allowed: now,
+            suppressed: 0,
+        });
+
+        let window_passed = now - entry.last_allowed >= self.window;
+
+        // Lines that stopped repeating for a while get the whole burst again
+        if window_passed && entry.suppressed == 0 {
+            entry.allowed = 0;
+        }
+
+        if entry.allowed >= self.burst && !window_passed {
+            entry.suppressed += 1;
+
+            return None;
+        }
+
+        entry.allowed += 1;
+        entry.last_allowed = now;
+
+        Some(std::mem::take(&mut entry.suppressed))
+    }
+}
+
+/// Returns suffix telling how many lines were held back, empty if none were
+pub fn suppressed_suffix(suppressed: u64) -> String {
+    match suppressed {
+        0 => String::new(),
+        suppressed => format!(" ({} more suppressed)", suppressed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_WINDOW: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn burst_is_let_through() {
+        let mut limiter = RateLimiter::new(3, TEST_WINDOW);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("error", now), Some(0));
+        }
+
+        assert_eq!(limiter.check_at("error", now), None);
+    }
+
+    #[test]
+    fn keys_are_limited_separately() {
+        let mut limiter = RateLimiter::new(1, TEST_WINDOW);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("first", now), Some(0));
+        assert_eq!(limiter.check_at("first", now), None);
+        assert_eq!(limiter.check_at("second", now), Some(0));
+    }
+
+    #[test]
+    fn held_back_lines_are_counted_in_the_next_one() {
+        let mut limiter = RateLimiter::new(1, TEST_WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at("error", start), Some(0));
+
+        for second in 1..=4 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(limiter.check_at("error", now), None);
+        }
+
+        assert_eq!(limiter.check_at("error", start + TEST_WINDOW), Some(4));
+
+        // Line keeps repeating, so only one per window is let through after that
+        let next = start + TEST_WINDOW + Duration::from_secs(1);
+        assert_eq!(limiter.check_at("error", next), None);
+    }
+
+    #[test]
+    fn quiet_window_brings_the_burst_back() {
+        let mut limiter = RateLimiter::new(2, TEST_WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at("error", start), Some(0));
+        assert_eq!(limiter.check_at("error", start), Some(0));
+
+        let later = start + TEST_WINDOW;
+        assert_eq!(limiter.check_at("error", later), Some(0));
+        assert_eq!(limiter.check_at("error", later), Some(0));
+        assert_eq!(limiter.check_at("error", later), None);
+    }
+}
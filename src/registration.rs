@@ -0,0 +1,323 @@
+use openaction::EventHandlerResult;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    TRACKER, aliases,
+    mappings::Kind,
+    outbound::{OpenDeck, Outbound},
+};
+
+/// How many times registration is attempted before giving up on it
+const REGISTER_ATTEMPTS: u32 = 5;
+
+/// Delay before the second attempt, growing with every next one
+const REGISTER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Registering,
+    Registered,
+}
+
+/// Registration states by device id, devices that are not here are not registered
+static STATES: LazyLock<Mutex<HashMap<String, State>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// How long unplugged device stays registered, in case it's back right away
+const UNPLUG_GRACE: Duration = Duration::from_millis(500);
+
+/// Unplugged devices waiting out the grace period by id, with the number of the unplug
+static UNPLUGGED: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_UNPLUG: AtomicU64 = AtomicU64::new(0);
+
+/// Devices OpenDeck could still have registered, because deregistering them failed
+static FAILED_DEREGISTRATIONS: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Returns ids of the devices registered in OpenDeck right now, sorted
+///
+/// Registration states are only changed by [register] and [deregister], so any task can read them
+pub async fn registered() -> Vec<String> {
+    let mut ids: Vec<String> = STATES
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, state)| **state == State::Registered)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    ids.sort();
+
+    ids
+}
+
+/// Registers device in OpenDeck, retrying failed attempts
+///
+/// Device that is registered already, or is being registered right now, is not registered again,
+/// because OpenDeck would show it twice
+pub async fn register(id: &str, kind: &Kind, outbound: &impl Outbound) {
+    {
+        // Unplug is checked under the same lock it's started with, so it can't start in between
+        let mut states = STATES.lock().await;
+
+        if UNPLUGGED.lock().await.remove(id).is_some() {
+            drop(states);
+
+            log::info!(
+                "Device {} is back within {:?}, keeping it registered",
+                id,
+                UNPLUG_GRACE
+            );
+
+            // OpenDeck still thinks device shows the images it got before the unplug
+            outbound.rerender_images(id).await.ok();
+
+            return;
+        }
+
+        if let Some(state) = states.get(id) {
+            log::debug!("Not registering device {}, it's {:?} already", id, state);
+            return;
+        }
+
+        states.insert(id.to_string(), State::Registering);
+    }
+
+    // OpenDeck refuses to register device it still has, so deregister it first
+    if FAILED_DEREGISTRATIONS.lock().await.contains(id) {
+        log::info!(
+            "Deregistering device {} before registering it, previous deregistration failed",
+            id
+        );
+
+        if outbound.deregister_device(id).await.is_ok() {
+            FAILED_DEREGISTRATIONS.lock().await.remove(id);
+        }
+    }
+
+    let name = aliases::display_name(id, kind).await;
+    let mut result: EventHandlerResult = Ok(());
+
+    for attempt in 1..=REGISTER_ATTEMPTS {
+        if attempt > 1 {
+            tokio::time::sleep(REGISTER_RETRY_DELAY * (attempt - 1)).await;
+        }
+
+        result = outbound.register_device(id, kind, &name).await;
+
+        let Err(err) = &result else {
+            break;
+        };
+
+        log::warn!(
+            "Unable to register device {} (attempt {} of {}): {}",
+            id,
+            attempt,
+            REGISTER_ATTEMPTS,
+            err
+        );
+    }
+
+    let mut states = STATES.lock().await;
+
+    if result.is_err() {
+        log::error!("Giving up registering device {}", id);
+        states.remove(id);
+
+        return;
+    }
+
+    // Device could be deregistered while it was being registered, so undo the registration
+    if states.get(id) != Some(&State::Registering) {
+        drop(states);
+
+        log::info!("Device {} was deregistered while registering", id);
+
+        if outbound.deregister_device(id).await.is_err() {
+            FAILED_DEREGISTRATIONS.lock().await.insert(id.to_string());
+        }
+
+        return;
+    }
+
+    states.insert(id.to_string(), State::Registered);
+}
+
+/// Deregisters device from OpenDeck, unless it was never registered
+pub async fn deregister(id: &str, outbound: &impl Outbound) {
+    match STATES.lock().await.remove(id) {
+        Some(State::Registered) => {}
+        // Attempt in progress deregisters device once it succeeds
+        Some(State::Registering) | None => return,
+    }
+
+    match outbound.deregister_device(id).await {
+        Ok(()) => {
+            FAILED_DEREGISTRATIONS.lock().await.remove(id);
+        }
+        Err(err) => {
+            log::warn!("Unable to deregister device {}: {}", id, err);
+            FAILED_DEREGISTRATIONS.lock().await.insert(id.to_string());
+        }
+    }
+}
+
+/// Deregisters unplugged device once the grace period is over, unless it's registered again before that
+///
+/// Hubs that lose power for a moment re-enumerate all the devices behind them, so OpenDeck
+/// only learns about devices that are gone for longer than that
+pub async fn unplugged(id: &str) {
+    let Some(unplug) = start_grace(id, &OpenDeck).await else {
+        return;
+    };
+
+    let id = id.to_string();
+
+    TRACKER.lock().await.spawn(async move {
+        tokio::time::sleep(UNPLUG_GRACE).await;
+        grace_over(&id, unplug, &OpenDeck).await;
+    });
+}
+
+/// Starts grace period of the registered device, returning the number of the unplug,
+/// and deregisters devices that are not registered yet right away
+async fn start_grace(id: &str, outbound: &impl Outbound) -> Option<u64> {
+    // Registration can't finish or check for the unplug until the unplug is in place
+    let states = STATES.lock().await;
+
+    if states.get(id) != Some(&State::Registered) {
+        drop(states);
+        deregister(id, outbound).await;
+
+        return None;
+    }
+
+    let unplug = NEXT_UNPLUG.fetch_add(1, Ordering::Relaxed);
+    UNPLUGGED.lock().await.insert(id.to_string(), unplug);
+
+    Some(unplug)
+}
+
+/// Deregisters device once its grace period is over, unless it came back or was unplugged again since
+async fn grace_over(id: &str, unplug: u64, outbound: &impl Outbound) {
+    let mut unplugged = UNPLUGGED.lock().await;
+
+    if unplugged.get(id) != Some(&unplug) {
+        return;
+    }
+
+    unplugged.remove(id);
+    drop(unplugged);
+
+    log::info!("Device {} didn't come back, deregistering it", id);
+    deregister(id, outbound).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::tests::Recorder;
+
+    #[tokio::test]
+    async fn registered_device_is_not_registered_again() {
+        let recorder = Recorder::default();
+
+        register("192-A", &Kind::AKP153, &recorder).await;
+        register("192-A", &Kind::AKP153, &recorder).await;
+
+        assert_eq!(recorder.events().len(), 1);
+        assert!(recorder.events()[0].starts_with("register_device 192-A AKP153 "));
+        assert!(registered().await.contains(&"192-A".to_string()));
+    }
+
+    #[tokio::test]
+    async fn device_registered_twice_at_once_is_registered_once() {
+        let recorder = Recorder::default();
+
+        tokio::join!(
+            register("192-B", &Kind::AKP153, &recorder),
+            register("192-B", &Kind::AKP153, &recorder)
+        );
+
+        assert_eq!(recorder.events().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn device_that_is_not_registered_is_not_deregistered() {
+        let recorder = Recorder::default();
+
+        deregister("217-A", &recorder).await;
+
+        assert!(recorder.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn registered_device_is_deregistered_once() {
+        let recorder = Recorder::default();
+
+        register("217-B", &Kind::AKP153, &recorder).await;
+        deregister("217-B", &recorder).await;
+        deregister("217-B", &recorder).await;
+
+        assert_eq!(recorder.events()[1..], ["deregister_device 217-B"]);
+        assert!(!registered().await.contains(&"217-B".to_string()));
+    }
+
+    #[tokio::test]
+    async fn device_back_within_grace_stays_registered() {
+        let recorder = Recorder::default();
+
+        register("217-C", &Kind::AKP153, &recorder).await;
+        let unplug = start_grace("217-C", &recorder).await.unwrap();
+        register("217-C", &Kind::AKP153, &recorder).await;
+        grace_over("217-C", unplug, &recorder).await;
+
+        assert_eq!(recorder.events()[1..], ["rerender_images 217-C"]);
+        assert!(registered().await.contains(&"217-C".to_string()));
+    }
+
+    #[tokio::test]
+    async fn device_gone_for_grace_is_deregistered() {
+        let recorder = Recorder::default();
+
+        register("217-D", &Kind::AKP153, &recorder).await;
+        let unplug = start_grace("217-D", &recorder).await.unwrap();
+        grace_over("217-D", unplug, &recorder).await;
+
+        assert_eq!(recorder.events()[1..], ["deregister_device 217-D"]);
+        assert!(!registered().await.contains(&"217-D".to_string()));
+    }
+
+    #[tokio::test]
+    async fn unplugged_device_that_is_not_registered_has_no_grace() {
+        let recorder = Recorder::default();
+
+        assert_eq!(start_grace("217-E", &recorder).await, None);
+        assert!(recorder.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn device_is_deregistered_before_registering_if_deregistration_failed() {
+        let recorder = Recorder::default();
+
+        register("217-F", &Kind::AKP153, &recorder).await;
+        deregister("217-F", &Recorder::failing(1)).await;
+        register("217-F", &Kind::AKP153, &recorder).await;
+
+        let events = recorder.events();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1], "deregister_device 217-F");
+        assert!(events[2].starts_with("register_device 217-F "));
+    }
+}
@@ -0,0 +1,181 @@
+use mirajazz::{device::list_devices, types::HidDeviceInfo};
+use std::fmt::Write;
+
+use crate::{
+    aliases::KNOWN_FILE,
+    logging::LOG_FILE,
+    mappings::{Kind, QUERIES},
+    paths::{cache_file, state_file},
+    stats::STATS_FILE,
+};
+
+/// File report is written to, next to the other plugin state
+const REPORT_FILE: &str = "report.txt";
+
+/// How many of the last log lines are included
+const LOG_LINES: usize = 200;
+
+/// Where udev rules of the plugin are usually installed
+#[cfg(target_os = "linux")]
+const UDEV_RULES: [&str; 3] = [
+    "/etc/udev/rules.d/40-opendeck-akp153.rules",
+    "/usr/lib/udev/rules.d/40-opendeck-akp153.rules",
+    "/lib/udev/rules.d/40-opendeck-akp153.rules",
+];
+
+/// Hides all but the last 4 characters, so serial numbers can be told apart without being disclosed
+fn redact(value: &str) -> String {
+    let count = value.chars().count();
+
+    value
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if i + 4 < count { '*' } else { c })
+        .collect()
+}
+
+/// Replaces serial numbers and user name in the text with their redacted versions
+fn redact_text(text: &str, serials: &[String]) -> String {
+    let mut text = text.to_string();
+
+    for serial in serials.iter().filter(|serial| serial.len() > 4) {
+        text = text.replace(serial.as_str(), &redact(serial));
+    }
+
+    for name in ["USER", "USERNAME"] {
+        if let Some(user) = std::env::var(name).ok().filter(|user| !user.is_empty()) {
+            text = text.replace(user.as_str(), "<user>");
+        }
+    }
+
+    text
+}
+
+#[cfg(target_os = "linux")]
+fn check_udev_rules(report: &mut String, devices: &[(u16, u16)]) {
+    let Some((path, rules)) = UDEV_RULES
+        .iter()
+        .find_map(|path| Some((path, std::fs::read_to_string(path).ok()?)))
+    else {
+        writeln!(report, "udev rules: not installed").unwrap();
+        return;
+    };
+
+    writeln!(report, "udev rules: {}", path).unwrap();
+
+    for (vid, pid) in devices {
+        let covered = rules.contains(&format!(
+            "ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\"",
+            vid, pid
+        ));
+
+        writeln!(report, "  {:04x}:{:04x} covered: {}", vid, pid, covered).unwrap();
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_udev_rules(_report: &mut String, _devices: &[(u16, u16)]) {
+    // udev is only used on Linux
+}
+
+/// Builds report with everything that helps to debug issues, with serial numbers and user name redacted
+pub async fn build() -> String {
+    let mut report = String::new();
+    let mut serials = Vec::new();
+    let mut devices = Vec::new();
+
+    writeln!(report, "Plugin version: {}", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(
+        report,
+        "OS: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+    .unwrap();
+
+    writeln!(report, "\nDetected devices:").unwrap();
+
+    match list_devices(&QUERIES).await {
+        Ok(found) => {
+            for dev in found {
+                let info: &HidDeviceInfo = &dev;
+                let kind = Kind::from_vid_pid(info.vendor_id, info.product_id);
+
+                writeln!(
+                    report,
+                    "  {:04x}:{:04x} usage {:04x}:{:04x}, serial {}, kind {:?}",
+                    info.vendor_id,
+                    info.product_id,
+                    info.usage_page,
+                    info.usage_id,
+                    info.serial_number
+                        .as_deref()
+                        .map(redact)
+                        .unwrap_or("none".to_string()),
+                    kind
+                )
+                .unwrap();
+
+                serials.extend(info.serial_number.clone());
+                devices.push((info.vendor_id, info.product_id));
+            }
+        }
+        Err(err) => writeln!(report, "  Unable to list devices: {}", err).unwrap(),
+    }
+
+    writeln!(report).unwrap();
+    check_udev_rules(&mut report, &devices);
+
+    // Sessions are only known to the running plugin, not to the one started to write the report
+    writeln!(report, "\nConnections:").unwrap();
+
+    for session in crate::stats::sessions().await {
+        writeln!(report, "  {}", session).unwrap();
+    }
+
+    writeln!(
+        report,
+        "\nRegistered in OpenDeck: {}",
+        crate::registration::registered().await.join(", ")
+    )
+    .unwrap();
+
+    for (title, file) in [("Key presses", STATS_FILE), ("Known devices", KNOWN_FILE)] {
+        let content = tokio::fs::read_to_string(state_file(file))
+            .await
+            .unwrap_or_else(|err| format!("Unable to read {}: {}", file, err));
+
+        writeln!(report, "\n{}:\n{}", title, content).unwrap();
+    }
+
+    writeln!(report, "\nLast {} log lines:", LOG_LINES).unwrap();
+
+    match tokio::fs::read_to_string(state_file(LOG_FILE)).await {
+        Ok(log) => {
+            let lines: Vec<&str> = log.lines().collect();
+
+            for line in &lines[lines.len().saturating_sub(LOG_LINES)..] {
+                writeln!(report, "{}", line).unwrap();
+            }
+        }
+        Err(err) => writeln!(
+            report,
+            "Unable to read {}, enable `logToFile` setting to get logs here: {}",
+            LOG_FILE, err
+        )
+        .unwrap(),
+    }
+
+    redact_text(&report, &serials)
+}
+
+/// Writes report to the file, returning its full path
+pub async fn write() -> Result<String, std::io::Error> {
+    let path = cache_file(REPORT_FILE);
+
+    tokio::fs::write(&path, build().await).await?;
+
+    let path = tokio::fs::canonicalize(path).await?;
+
+    Ok(path.display().to_string())
+}
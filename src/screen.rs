@@ -0,0 +1,52 @@
+use mirajazz::{device::Device, error::MirajazzError};
+
+/// Writes plugin makes to the device, so code making them can be run without a real device
+pub trait Screen {
+    async fn write_image(&self, key: u8, data: &[u8]) -> Result<(), MirajazzError>;
+    async fn flush(&self) -> Result<(), MirajazzError>;
+}
+
+impl Screen for Device {
+    async fn write_image(&self, key: u8, data: &[u8]) -> Result<(), MirajazzError> {
+        Device::write_image(self, key, data).await
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        Device::flush(self).await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records writes instead of making them, so tests can check what device would get
+    #[derive(Default)]
+    pub struct Recorder {
+        writes: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        /// Returns recorded writes, like `write_image 3 1024`, in the order they were made
+        pub fn writes(&self) -> Vec<String> {
+            self.writes.lock().unwrap().clone()
+        }
+
+        fn record(&self, write: String) -> Result<(), MirajazzError> {
+            self.writes.lock().unwrap().push(write);
+
+            Ok(())
+        }
+    }
+
+    impl Screen for Recorder {
+        async fn write_image(&self, key: u8, data: &[u8]) -> Result<(), MirajazzError> {
+            self.record(format!("write_image {} {}", key, data.len()))
+        }
+
+        async fn flush(&self) -> Result<(), MirajazzError> {
+            self.record("flush".to_string())
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    mappings::{ALL_KINDS, Kind, get_image_format_for_key},
+    pipeline::{self, Options},
+};
+
+/// Side of the key image that ends up bright, for a source image with bright left half
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+/// Sizes and orientation of key images devices are known to show right, kept apart from
+/// [get_image_format_for_key], so a mistake made there is caught here
+fn reference(kind: &Kind, key: u8) -> ((usize, usize), Side) {
+    let size = match (kind.protocol_version(), key) {
+        (1, _) => (85, 85),
+        (_, 5 | 11 | 17) => (82, 82),
+        _ => (95, 95),
+    };
+
+    // All the kinds expect images mirrored on both axes, and all but the portrait one rotated by 90 degrees
+    let side = match kind {
+        Kind::TMICESC => Side::Right,
+        _ => Side::Bottom,
+    };
+
+    (size, side)
+}
+
+/// Finds the brightest half of the image
+fn bright_side(image: &DynamicImage) -> Side {
+    let image = image.to_luma8();
+    let (width, height) = image.dimensions();
+
+    let mut sums = [0u64; 4];
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let value = pixel.0[0] as u64;
+
+        sums[if y < height / 2 { 0 } else { 1 }] += value;
+        sums[if x < width / 2 { 2 } else { 3 }] += value;
+    }
+
+    let sides = [Side::Top, Side::Bottom, Side::Left, Side::Right];
+
+    sides[(0..4).max_by_key(|&side| sums[side]).unwrap()] // Array is never empty
+}
+
+/// Renders test image for the key of the kind, returning what's wrong with the result
+fn check_key(kind: &Kind, key: u8) -> Result<(), String> {
+    let format = get_image_format_for_key(kind, key);
+
+    let mut source = RgbImage::new(2, 1);
+    source.put_pixel(0, 0, Rgb([255, 255, 255]));
+
+    let data = pipeline::process(
+        &format,
+        &Options::default(),
+        DynamicImage::ImageRgb8(source),
+    )
+    .map_err(|err| format!("unable to encode: {}", err))?;
+
+    let (expected_size, expected_side) = reference(kind, key);
+
+    let size = pipeline::jpeg_size(&data).ok_or("output is not a baseline JPEG")?;
+
+    if size != expected_size {
+        return Err(format!("size is {:?} instead of {:?}", size, expected_size));
+    }
+
+    // Anything bigger than raw pixels means encoder went wrong
+    if data.len() > size.0 * size.1 * 3 {
+        return Err(format!("output takes {} bytes", data.len()));
+    }
+
+    let image = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg)
+        .map_err(|err| format!("unable to decode output: {}", err))?;
+
+    let side = bright_side(&image);
+
+    if side != expected_side {
+        return Err(format!(
+            "image is oriented wrong, bright half is on {:?} side instead of {:?}",
+            side, expected_side
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks images of every key of every kind against the reference, logging all the mismatches
+///
+/// Most kinds share the same formats, so every distinct format is rendered once, which keeps the check within a few ms
+pub fn run() -> bool {
+    let started = Instant::now();
+
+    let mut results: HashMap<String, Result<(), String>> = HashMap::new();
+    let mut passed = true;
+
+    for kind in ALL_KINDS.iter() {
+        for key in kind.matrix_keys() {
+            let format = format!(
+                "{:?} {:?}",
+                get_image_format_for_key(kind, key),
+                reference(kind, key)
+            );
+
+            let result = results
+                .entry(format)
+                .or_insert_with(|| check_key(kind, key));
+
+            if let Err(err) = result {
+                log::error!("Self-check failed for key {} of {:?}: {}", key, kind, err);
+                passed = false;
+            }
+        }
+    }
+
+    log::info!(
+        "Self-check of {} image formats {} in {:?}",
+        results.len(),
+        if passed { "passed" } else { "failed" },
+        started.elapsed()
+    );
+
+    passed
+}
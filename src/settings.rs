@@ -0,0 +1,243 @@
+use mirajazz::types::ImageRotation;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    mappings::Kind,
+    pipeline::{ColorCurves, CropAnchor},
+};
+
+/// Brightness of images on dimmed keys, unless settings specify another one
+const DEFAULT_DIM_LEVEL: u8 = 40;
+
+/// Range of the zoom, icons shrunk or cropped further are unreadable
+const MIN_ZOOM: f32 = 0.5;
+const MAX_ZOOM: f32 = 2.0;
+
+/// Plugin settings, stored by OpenDeck in the plugin's global settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    /// Forces USB power of the device to stay `on`, disabling autosuspend (Linux only)
+    pub disable_autosuspend: bool,
+    /// Maximum number of images encoded at the same time across all devices, defaults to number of cores
+    pub max_concurrent_encodes: Option<usize>,
+    /// Limit for images kept in memory to restore devices after reset, in bytes
+    pub image_cache_size: Option<usize>,
+    /// Limit for each of the caches of decoded and encoded images reused for the same icons, in bytes
+    pub transform_cache_size: Option<usize>,
+    /// Limit for all the image caches together, in bytes
+    pub memory_limit: Option<usize>,
+    /// Id of the device to blink keys of, so it can be found physically
+    pub identify: Option<String>,
+    /// Key press to send to OpenDeck as if it was made on the device, for other software to trigger actions
+    pub simulate_press: Option<SimulatedPress>,
+    /// Id of the device to check if it's still reachable
+    pub ping: Option<String>,
+    /// Skips logging on the input path, so key presses reach OpenDeck as soon as possible
+    pub low_latency: bool,
+    /// Logs how long stages of every image took, by trace id of the image
+    pub trace_timings: bool,
+    /// Writes issue report when switched on
+    pub report: bool,
+    /// Resets key press counters when switched on
+    pub reset_press_counters: bool,
+    /// Kinds to use by device id instead of the detected ones, for devices that are detected wrong
+    pub kind_overrides: HashMap<String, String>,
+    /// Extra commands sent during init by device id, for clones that show nothing until they get them
+    pub extended_init: HashMap<String, Vec<Vec<u8>>>,
+    /// Seconds without any input after which all the devices are put to sleep, until something is pressed
+    pub eco_mode_timeout: Option<u64>,
+    /// Ids of the devices plugin closes for a while, so other software can use them without unplugging
+    pub paused_devices: HashSet<String>,
+    /// Deregisters paused devices from OpenDeck instead of keeping them registered
+    pub deregister_paused: bool,
+    /// Ids of the devices that are registered without writing a test frame during init first
+    pub skip_test_frame: HashSet<String>,
+    /// Ids of the devices plugin leaves alone, so other software can use them
+    pub excluded_devices: HashSet<String>,
+    /// Lets a new "v1" device take over id of a different "v1" model it replaced, so profiles carry over
+    pub migrate_v1_ids: bool,
+    /// Groups of devices showing the same images, first device of the group is the only one registered in OpenDeck
+    pub mirror_groups: Vec<Vec<String>>,
+    /// Log level, one of `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: Option<String>,
+    /// Modules to log, like `opendeck_akp153::device`, everything is logged if empty
+    pub log_modules: Vec<String>,
+    /// Copies logs to a file in the plugin directory, so they can be attached to issues
+    pub log_to_file: bool,
+    /// Points of the images that stay in view when they are cropped to fill the keys, by device id, then by key
+    pub crop_anchors: HashMap<String, HashMap<u8, CropAnchor>>,
+    /// Zoom of the images by device id, from 0.5 to 2.0, to cut off borders baked into icons
+    pub image_zoom: HashMap<String, f32>,
+    /// Zoom of the images by device id, then by key, overriding the one of the device
+    pub key_zoom: HashMap<String, HashMap<u8, f32>>,
+    /// OpenDeck key position by device id to show health of the device connection on instead of the image of the key
+    pub status_tile: HashMap<String, u8>,
+    /// Number of brightness levels device really has by device id, brightness is snapped to the nearest one
+    pub brightness_levels: HashMap<String, u8>,
+    /// Ids of the devices to show inverted images on
+    pub invert_images: HashSet<String>,
+    /// Ids of the devices to show images with boosted contrast on
+    pub boost_contrast: HashSet<String>,
+    /// Ids of the devices to fade images in on, instead of showing them right away
+    pub fade_images: HashSet<String>,
+    /// Ids of the devices to show darker images on while keys are held
+    pub pressed_effect: HashSet<String>,
+    /// Keys to show darker images on by device id, so unused keys don't draw attention
+    pub dim_keys: HashMap<String, Vec<u8>>,
+    /// Brightness of images on dimmed keys in percent
+    pub dim_level: Option<u8>,
+    /// Ids of the devices that get images already rotated and mirrored the way device expects them
+    pub pre_transformed_images: HashSet<String>,
+    /// JPEG quality from 1 to 100 by device id, higher values give cleaner edges at the cost of bandwidth
+    pub jpeg_quality: HashMap<String, u8>,
+    /// Rotation of the images in degrees by device id, overriding the one device kind expects
+    pub image_rotation: HashMap<String, u16>,
+    /// Color curves by device id, overriding the ones device kind ships with
+    pub color_curves: HashMap<String, ColorCurves>,
+}
+
+/// Key press sent to OpenDeck without touching the device
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SimulatedPress {
+    pub device: String,
+    /// Key position in OpenDeck
+    pub key: u8,
+    /// How long key is held, in milliseconds
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+impl Settings {
+    /// Parses settings from the global settings payload, returning [None] if payload is malformed
+    pub fn from_value(value: openaction::SettingsValue) -> Option<Self> {
+        // OpenDeck sends null if settings were never saved
+        if value.is_null() {
+            return Some(Self::default());
+        }
+
+        match serde_json::from_value(value) {
+            Ok(settings) => Some(settings),
+            Err(err) => {
+                log::error!("Failed to parse settings: {}", err);
+
+                None
+            }
+        }
+    }
+
+    /// Returns rotation images of the device have to be sent with, if it's overridden with a valid one
+    pub fn rotation_override(&self, id: &str) -> Option<ImageRotation> {
+        match self.image_rotation.get(id)? {
+            0 => Some(ImageRotation::Rot0),
+            90 => Some(ImageRotation::Rot90),
+            180 => Some(ImageRotation::Rot180),
+            270 => Some(ImageRotation::Rot270),
+            degrees => {
+                log::error!(
+                    "Rotation of {} must be 0, 90, 180 or 270 degrees, not {}, ignoring it",
+                    id,
+                    degrees
+                );
+
+                None
+            }
+        }
+    }
+
+    /// Returns kind device has to be treated as, if it's overridden with a known kind
+    pub fn kind_override(&self, id: &str) -> Option<Kind> {
+        let name = self.kind_overrides.get(id)?;
+
+        match serde_json::from_value(serde_json::Value::String(name.clone())) {
+            Ok(kind) => Some(kind),
+            Err(_) => {
+                log::error!("Unknown kind {} in override for {}, ignoring it", name, id);
+
+                None
+            }
+        }
+    }
+
+    /// Returns brightness of the key images in percent, if key is dimmed
+    pub fn dim_level(&self, id: &str, key: u8) -> Option<u8> {
+        self.dim_keys
+            .get(id)
+            .filter(|keys| keys.contains(&key))
+            .map(|_| self.dim_level.unwrap_or(DEFAULT_DIM_LEVEL))
+    }
+
+    /// Snaps brightness in percent to the nearest level device really has, if their number is known
+    pub fn device_brightness(&self, id: &str, brightness: u8) -> u8 {
+        let brightness = brightness.min(100);
+
+        let Some(levels) = self
+            .brightness_levels
+            .get(id)
+            .filter(|levels| **levels >= 2)
+        else {
+            return brightness;
+        };
+
+        let step = 100.0 / (*levels - 1) as f32;
+        let snapped = ((brightness as f32 / step).round() * step).round() as u8;
+
+        if snapped != brightness {
+            log::info!(
+                "Device {} only has {} brightness levels, using {} instead of {}",
+                id,
+                levels,
+                snapped,
+                brightness
+            );
+        }
+
+        snapped
+    }
+
+    /// Returns zoom of the key images, if they are zoomed, clamped to the supported range
+    pub fn zoom(&self, id: &str, key: u8) -> Option<f32> {
+        let zoom = self
+            .key_zoom
+            .get(id)
+            .and_then(|keys| keys.get(&key))
+            .or_else(|| self.image_zoom.get(id))?;
+
+        match zoom.clamp(MIN_ZOOM, MAX_ZOOM) {
+            zoom if zoom == 1.0 || zoom.is_nan() => None,
+            zoom => Some(zoom),
+        }
+    }
+
+    /// Checks if images of the device have to be processed differently with other settings
+    pub fn image_options_changed(&self, other: &Settings, id: &str) -> bool {
+        self.crop_anchors.get(id) != other.crop_anchors.get(id)
+            || self.image_zoom.get(id) != other.image_zoom.get(id)
+            || self.key_zoom.get(id) != other.key_zoom.get(id)
+            || self.status_tile.get(id) != other.status_tile.get(id)
+            || self.invert_images.contains(id) != other.invert_images.contains(id)
+            || self.boost_contrast.contains(id) != other.boost_contrast.contains(id)
+            || self.jpeg_quality.get(id) != other.jpeg_quality.get(id)
+            || self.color_curves.get(id) != other.color_curves.get(id)
+            || self.dim_keys.get(id) != other.dim_keys.get(id)
+            || (self.dim_keys.contains_key(id) && self.dim_level != other.dim_level)
+    }
+
+    /// Returns id of the device this device mirrors, if it's a secondary device of a mirror group
+    pub fn mirror_primary(&self, id: &str) -> Option<&String> {
+        self.mirror_groups
+            .iter()
+            .find(|group| group.iter().skip(1).any(|member| member == id))
+            .and_then(|group| group.first())
+    }
+
+    /// Returns ids of the devices mirroring this device, if it's a primary device of a mirror group
+    pub fn mirror_members(&self, id: &str) -> &[String] {
+        self.mirror_groups
+            .iter()
+            .find(|group| group.first().is_some_and(|primary| primary == id))
+            .map(|group| &group[1..])
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,194 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use mirajazz::{device::Device, state::DeviceStateUpdate};
+use std::time::{Duration, Instant};
+
+use crate::{
+    device::connect,
+    inputs::{opendeck_to_device, process_input},
+    mappings::{CandidateDevice, Kind, get_image_format_for_key},
+    pipeline::{self, Options},
+    watcher::get_candidates,
+};
+
+/// Brightness set during the test
+const BRIGHTNESS: u8 = 50;
+
+/// How long to wait for a key press
+const PRESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stages of the test, with the exit code used when the stage fails
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    Find = 2,
+    Connect = 3,
+    Brightness = 4,
+    Pattern = 5,
+    Press = 6,
+    Clear = 7,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Find => "find",
+            Self::Connect => "connect",
+            Self::Brightness => "brightness",
+            Self::Pattern => "pattern",
+            Self::Press => "press",
+            Self::Clear => "clear",
+        }
+    }
+}
+
+/// Prints progress line in `key=value` form, so wrappers can parse it
+fn report(stage: Stage, status: &str, detail: impl std::fmt::Display) {
+    println!(
+        "smoke-test stage={} status={} detail={:?}",
+        stage.name(),
+        status,
+        detail.to_string()
+    );
+}
+
+/// Draws key image with a distinct color per key and `position + 1` white blocks on it, so keys can be counted
+fn pattern(kind: &Kind, position: u8) -> DynamicImage {
+    let (width, height) = get_image_format_for_key(kind, position).size;
+    let (width, height) = (width as u32, height as u32);
+
+    let hue = position as f32 * 360.0 / kind.key_count() as f32;
+    let channel = |offset: f32| {
+        let distance = ((hue - offset).rem_euclid(360.0) - 180.0).abs();
+        ((distance / 180.0) * 160.0) as u8
+    };
+
+    let mut image = RgbImage::from_pixel(
+        width,
+        height,
+        Rgb([channel(0.0), channel(120.0), channel(240.0)]),
+    );
+
+    // Blocks in rows of 5, like on dice, taking the top of the key
+    let block = width / 8;
+
+    for index in 0..position as u32 + 1 {
+        let x = block + (index % 5) * block * 3 / 2;
+        let y = block + (index / 5) * block * 3 / 2;
+
+        for dy in 0..block {
+            for dx in 0..block {
+                if x + dx < width && y + dy < height {
+                    image.put_pixel(x + dx, y + dy, Rgb([255, 255, 255]));
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+async fn find(id: Option<&str>) -> Result<CandidateDevice, String> {
+    let candidates = get_candidates().await.map_err(|err| err.to_string())?;
+
+    candidates
+        .into_iter()
+        .find(|candidate| id.is_none_or(|id| candidate.id == id))
+        .ok_or_else(|| match id {
+            Some(id) => format!("device {} is not connected", id),
+            None => "no supported devices connected".to_string(),
+        })
+}
+
+async fn show_pattern(kind: &Kind, device: &Device) -> Result<(), String> {
+    for position in kind.matrix_keys() {
+        let format = get_image_format_for_key(kind, position);
+        let data = pipeline::process(&format, &Options::default(), pattern(kind, position))
+            .map_err(|err| err.to_string())?;
+
+        device
+            .write_image(opendeck_to_device(position), &data)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    device.flush().await.map_err(|err| err.to_string())
+}
+
+async fn wait_for_press(kind: &Kind, device: &Device) -> Result<u8, String> {
+    let reader = device.get_reader(process_input);
+    let started = Instant::now();
+
+    while let Some(left) = PRESS_TIMEOUT.checked_sub(started.elapsed()) {
+        let updates = reader
+            .read(Some(left))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        for update in updates {
+            if let DeviceStateUpdate::ButtonDown(key) = update {
+                return Ok(kind.grid_position(key).unwrap_or(key));
+            }
+        }
+    }
+
+    Err(format!("no key pressed within {:?}", PRESS_TIMEOUT))
+}
+
+/// Drives the first supported device, or the one with the given id, through all the stages,
+/// returning exit code
+pub async fn run(id: Option<&str>) -> i32 {
+    let candidate = match find(id).await {
+        Ok(candidate) => candidate,
+        Err(err) => {
+            report(Stage::Find, "failed", err);
+            return Stage::Find as i32;
+        }
+    };
+
+    report(Stage::Find, "ok", &candidate.id);
+
+    let device = match connect(&candidate).await {
+        Ok(device) => device,
+        Err(err) => {
+            report(Stage::Connect, "failed", err);
+            return Stage::Connect as i32;
+        }
+    };
+
+    report(Stage::Connect, "ok", candidate.kind.human_name());
+
+    if let Err(err) = device.set_brightness(BRIGHTNESS).await {
+        report(Stage::Brightness, "failed", err);
+        return Stage::Brightness as i32;
+    }
+
+    report(Stage::Brightness, "ok", BRIGHTNESS);
+
+    if let Err(err) = show_pattern(&candidate.kind, &device).await {
+        report(Stage::Pattern, "failed", err);
+        return Stage::Pattern as i32;
+    }
+
+    report(Stage::Pattern, "ok", "press any key");
+
+    match wait_for_press(&candidate.kind, &device).await {
+        Ok(key) => report(Stage::Press, "ok", key),
+        Err(err) => {
+            report(Stage::Press, "failed", err);
+            return Stage::Press as i32;
+        }
+    }
+
+    let cleared = async {
+        device.clear_all_button_images().await?;
+        device.flush().await
+    };
+
+    if let Err(err) = cleared.await {
+        report(Stage::Clear, "failed", err);
+        return Stage::Clear as i32;
+    }
+
+    report(Stage::Clear, "ok", "");
+
+    0
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::paths::state_file;
+
+/// File key press counters are persisted to
+pub const STATS_FILE: &str = "stats.json";
+
+/// Counters are written to disk at most this often, so frequent presses don't cause constant writes
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Key press counters by device id, then by key
+static PRESSES: LazyLock<RwLock<HashMap<String, Vec<u64>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Images dropped because device has no such key, and the highest key they were for, by device id
+static DROPPED: LazyLock<RwLock<HashMap<String, (u64, u8)>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Counters of updates plugin dropped on purpose, by reason
+#[derive(Debug, Default)]
+pub struct DropStats {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl DropStats {
+    pub fn count(&mut self, reason: &'static str) {
+        *self.counts.entry(reason).or_default() += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl fmt::Display for DropStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect();
+
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+/// Dropped updates by device id
+static DROPS: LazyLock<RwLock<HashMap<String, DropStats>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Current or last connection of the device
+struct Session {
+    started: Instant,
+    connected: bool,
+    /// How many times device connected again since plugin started
+    reconnects: u64,
+    /// Images written and keys pressed during the connection
+    images: u64,
+    presses: u64,
+}
+
+/// Connections by device id, kept while plugin is running
+static SESSIONS: LazyLock<RwLock<HashMap<String, Session>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// How many of the last key presses latency percentiles are computed over
+const LATENCY_SAMPLES: usize = 256;
+
+/// Latency of a single key press is logged at most this often
+const LATENCY_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Recent times it took for key presses read from the device to be sent to OpenDeck
+#[derive(Default)]
+struct Latencies {
+    samples: VecDeque<Duration>,
+    logged: Option<Instant>,
+}
+
+impl Latencies {
+    /// Returns p50, p95 and p99 of the samples
+    fn percentiles(&self) -> Option<(Duration, Duration, Duration)> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut samples: Vec<Duration> = self.samples.iter().copied().collect();
+        samples.sort();
+
+        let percentile = |p: usize| samples[(samples.len() * p / 100).min(samples.len() - 1)];
+
+        Some((percentile(50), percentile(95), percentile(99)))
+    }
+}
+
+/// Key press latencies by device id
+static LATENCIES: LazyLock<RwLock<HashMap<String, Latencies>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Counters changed since they were persisted last time
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Counts key press for the device, which has `key_count` keys
+pub async fn count_press(id: &str, key: u8, key_count: usize) {
+    let mut presses = PRESSES.write().await;
+    let counters = presses.entry(id.to_string()).or_default();
+
+    if count(counters, key, key_count) {
+        DIRTY.store(true, Ordering::Release);
+    } else {
+        log::warn!(
+            "Not counting press of key {} of {}, it only has {} keys",
+            key,
+            id,
+            key_count
+        );
+    }
+
+    drop(presses);
+
+    if let Some(session) = SESSIONS.write().await.get_mut(id) {
+        session.presses += 1;
+    }
+}
+
+/// Counts key press, sizing counters for the device first, returns false if device has no such key
+///
+/// Counters loaded from the file, or counted for mirrored devices, can be of another kind with more keys,
+/// so they are only ever grown
+fn count(counters: &mut Vec<u64>, key: u8, key_count: usize) -> bool {
+    if key as usize >= key_count {
+        return false;
+    }
+
+    if counters.len() < key_count {
+        counters.resize(key_count, 0);
+    }
+
+    counters[key as usize] += 1;
+
+    true
+}
+
+/// Records how long it took for the key press read from the device to be sent to OpenDeck
+pub async fn record_latency(id: &str, latency: Duration) {
+    let mut latencies = LATENCIES.write().await;
+    let latencies = latencies.entry(id.to_string()).or_default();
+
+    if latencies.samples.len() == LATENCY_SAMPLES {
+        latencies.samples.pop_front();
+    }
+
+    latencies.samples.push_back(latency);
+
+    if latencies
+        .logged
+        .is_none_or(|logged| logged.elapsed() >= LATENCY_LOG_INTERVAL)
+    {
+        log::debug!("Key press of {} reached OpenDeck in {:?}", id, latency);
+        latencies.logged = Some(Instant::now());
+    }
+}
+
+/// Counts image written to the device
+pub async fn count_image(id: &str) {
+    if let Some(session) = SESSIONS.write().await.get_mut(id) {
+        session.images += 1;
+    }
+}
+
+/// Starts new connection of the device, counting it as a reconnect if device was connected before
+pub async fn connected(id: &str) {
+    let mut sessions = SESSIONS.write().await;
+
+    let reconnects = sessions
+        .get(id)
+        .map(|session| session.reconnects + 1)
+        .unwrap_or(0);
+
+    sessions.insert(
+        id.to_string(),
+        Session {
+            started: Instant::now(),
+            connected: true,
+            reconnects,
+            images: 0,
+            presses: 0,
+        },
+    );
+
+    DIRTY.store(true, Ordering::Release);
+}
+
+/// Returns how many times device connected again since plugin started
+pub async fn reconnects(id: &str) -> u64 {
+    SESSIONS
+        .read()
+        .await
+        .get(id)
+        .map(|session| session.reconnects)
+        .unwrap_or(0)
+}
+
+/// Finishes connection of the device, logging how long it lasted and how much it was used
+pub async fn disconnected(id: &str) {
+    let mut sessions = SESSIONS.write().await;
+
+    let Some(session) = sessions.get_mut(id) else {
+        return;
+    };
+
+    session.connected = false;
+
+    log::info!(
+        "Device {} was connected for {:?}, {} images written and {} keys pressed",
+        id,
+        Duration::from_secs(session.started.elapsed().as_secs()),
+        session.images,
+        session.presses
+    );
+
+    if let Some(drops) = DROPS.read().await.get(id).filter(|drops| drops.total() > 0) {
+        log::info!("Updates of {} dropped since plugin started: {}", id, drops);
+    }
+
+    DIRTY.store(true, Ordering::Release);
+}
+
+/// Describes connection of every device, one line per device
+pub async fn sessions() -> Vec<String> {
+    let paused = crate::SETTINGS.read().await.paused_devices.clone();
+
+    SESSIONS
+        .read()
+        .await
+        .iter()
+        .map(|(id, session)| {
+            format!(
+                "{} {} {:?} ago, {} reconnects since plugin started",
+                id,
+                if paused.contains(id) {
+                    "paused, last connected"
+                } else if session.connected {
+                    "connected"
+                } else {
+                    "last connected"
+                },
+                Duration::from_secs(session.started.elapsed().as_secs()),
+                session.reconnects
+            )
+        })
+        .collect()
+}
+
+/// Counts image dropped because device has no key at the position
+pub async fn count_dropped(id: &str, position: u8) {
+    let mut dropped = DROPPED.write().await;
+
+    let (count, highest) = dropped.entry(id.to_string()).or_default();
+    *count += 1;
+    *highest = (*highest).max(position);
+
+    drop(dropped);
+
+    count_drop(id, "images for missing keys").await;
+}
+
+/// Counts update of the device dropped for the reason
+pub async fn count_drop(id: &str, reason: &'static str) {
+    DROPS
+        .write()
+        .await
+        .entry(id.to_string())
+        .or_default()
+        .count(reason);
+
+    DIRTY.store(true, Ordering::Release);
+}
+
+/// Resets counters of all the devices
+pub async fn reset() {
+    log::info!("Resetting key press counters");
+
+    PRESSES.write().await.clear();
+    DIRTY.store(true, Ordering::Release);
+}
+
+async fn load() {
+    let data = match tokio::fs::read(state_file(STATS_FILE)).await {
+        Ok(data) => data,
+        Err(err) => {
+            log::info!("No key press counters loaded: {}", err);
+            return;
+        }
+    };
+
+    match serde_json::from_slice(&data) {
+        Ok(presses) => *PRESSES.write().await = presses,
+        Err(err) => log::error!("Unable to parse {}: {}", STATS_FILE, err),
+    }
+}
+
+/// Returns key press counters the plugin saved, by device id, for listing them without a running plugin
+pub async fn saved_presses() -> HashMap<String, Vec<u64>> {
+    load().await;
+
+    PRESSES.read().await.clone()
+}
+
+/// Logs counters, sessions and usage of all the devices, to tell what the plugin was doing
+async fn log_stats() {
+    log::debug!("Key press counters: {:?}", *PRESSES.read().await);
+
+    crate::encode::log_transform_stats().await;
+    crate::memory::log_usage().await;
+
+    for session in sessions().await {
+        log::debug!("Device {}", session);
+    }
+
+    let quarantined = crate::watchdog::quarantined().await;
+
+    if !quarantined.is_empty() {
+        log::debug!("Devices reconnecting too often: {:?}", quarantined);
+    }
+
+    for (id, latencies) in LATENCIES.read().await.iter() {
+        if let Some((p50, p95, p99)) = latencies.percentiles() {
+            log::debug!(
+                "Key press latency of {} over last {} presses: p50 {:?}, p95 {:?}, p99 {:?}",
+                id,
+                latencies.samples.len(),
+                p50,
+                p95,
+                p99
+            );
+        }
+    }
+
+    for (id, drops) in DROPS.read().await.iter() {
+        log::debug!("Dropped updates of {}: {}", id, drops);
+    }
+
+    for (id, (count, highest)) in DROPPED.read().await.iter() {
+        log::debug!(
+            "Dropped {} images for missing keys of {}, profile targets {} keys",
+            count,
+            id,
+            *highest as usize + 1
+        );
+    }
+}
+
+async fn persist() {
+    if !DIRTY.swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    // Map of strings to numbers always serializes
+    let data = serde_json::to_vec(&*PRESSES.read().await).unwrap();
+
+    if let Err(err) = tokio::fs::write(state_file(STATS_FILE), data).await {
+        log::error!("Unable to write {}: {}", STATS_FILE, err);
+    }
+}
+
+/// Loads counters, and periodically logs and persists them until cancelled
+pub async fn stats_task(token: CancellationToken) {
+    load().await;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(PERSIST_INTERVAL) => {
+                log_stats().await;
+                persist().await;
+            }
+            _ = token.cancelled() => break
+        }
+    }
+
+    persist().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_sized_from_key_count() {
+        for key_count in [15, 18, 32] {
+            let mut counters = Vec::new();
+
+            assert!(count(&mut counters, key_count as u8 - 1, key_count));
+            assert_eq!(counters.len(), key_count);
+            assert_eq!(counters[key_count - 1], 1);
+        }
+    }
+
+    #[test]
+    fn press_of_key_outside_of_device_is_not_counted() {
+        for key_count in [15, 18, 32] {
+            let mut counters = Vec::new();
+
+            assert!(!count(&mut counters, key_count as u8, key_count));
+            assert!(counters.iter().all(|counter| *counter == 0));
+        }
+    }
+
+    #[test]
+    fn counters_of_another_kind_are_kept() {
+        let mut counters = vec![1; 18];
+
+        assert!(count(&mut counters, 0, 15));
+        assert_eq!(counters, [vec![2], vec![1; 17]].concat());
+
+        assert!(count(&mut counters, 31, 32));
+        assert_eq!(counters.len(), 32);
+        assert_eq!(counters[31], 1);
+    }
+}
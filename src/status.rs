@@ -0,0 +1,135 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use std::time::Duration;
+
+use crate::{
+    DEVICES, SETTINGS, backlog,
+    device::{WRITE_FAILURE_LIMIT, device_kind, show_image, write_failures},
+    eco,
+    inputs::opendeck_to_device,
+    mappings::get_image_format_for_key,
+    stats,
+};
+
+/// How often the status tile is redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+const GOOD: Rgb<u8> = Rgb([0, 160, 0]);
+const WARNING: Rgb<u8> = Rgb([200, 150, 0]);
+const BAD: Rgb<u8> = Rgb([200, 0, 0]);
+
+/// Health of the device connection, every field is drawn as a colored bar
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Health {
+    /// Whether images are written, or keep failing
+    writes: Rgb<u8>,
+    /// How often device reconnected since plugin started
+    reconnects: Rgb<u8>,
+    /// Whether image quality is lowered, because device can't keep up with the images
+    backlog: Rgb<u8>,
+}
+
+impl Health {
+    async fn of(id: &str) -> Self {
+        let writes = match write_failures(id).await {
+            0 => GOOD,
+            1..WRITE_FAILURE_LIMIT => WARNING,
+            _ => BAD,
+        };
+
+        let reconnects = match stats::reconnects(id).await {
+            0 => GOOD,
+            1..3 => WARNING,
+            _ => BAD,
+        };
+
+        let backlog = match backlog::quality_reduction(id).await {
+            0 => GOOD,
+            1..20 => WARNING,
+            _ => BAD,
+        };
+
+        Self {
+            writes,
+            reconnects,
+            backlog,
+        }
+    }
+
+    /// Draws the bars from top to bottom, with gaps between them
+    fn draw(&self, width: u32, height: u32) -> DynamicImage {
+        let bars = [self.writes, self.reconnects, self.backlog];
+        let bar_height = height / (bars.len() as u32 * 2 + 1);
+
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let margin = x < bar_height || x >= width - bar_height;
+            let slot = y / bar_height.max(1);
+
+            match bars.get((slot / 2) as usize) {
+                Some(color) if slot % 2 == 1 && !margin => *color,
+                _ => Rgb([0, 0, 0]),
+            }
+        });
+
+        DynamicImage::ImageRgb8(image)
+    }
+}
+
+/// Returns OpenDeck position of the status tile of the device, if it has one
+pub async fn tile_position(id: &str) -> Option<u8> {
+    SETTINGS.read().await.status_tile.get(id).copied()
+}
+
+/// Keeps status tile of the device up to date while it's connected, redrawing it only when health changes
+pub async fn status_task(id: &str) {
+    let mut shown: Option<(u8, Health)> = None;
+
+    loop {
+        // Sleeping devices are not drawn on, the tile is drawn again once they wake up
+        let position = tile_position(id).await.filter(|_| !eco::asleep());
+
+        if let Some(position) = position {
+            let health = Health::of(id).await;
+
+            if shown != Some((position, health)) && write_tile(id, position, &health).await {
+                shown = Some((position, health));
+            }
+        } else {
+            shown = None;
+        }
+
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+/// Writes status tile to the key, returning false if it wasn't written
+async fn write_tile(id: &str, position: u8, health: &Health) -> bool {
+    let devices = DEVICES.read().await;
+    let Some(device) = devices.get(id) else {
+        return false;
+    };
+
+    let kind = device_kind(id, device).await;
+
+    if position as usize >= kind.key_count() {
+        log::error!(
+            "Status tile of {} is set to key {}, but device only has {} keys",
+            id,
+            position,
+            kind.key_count()
+        );
+        return false;
+    }
+
+    let position = kind.matrix_position(position);
+    let format = get_image_format_for_key(&kind, position);
+    let tile = health.draw(format.size.0 as u32, format.size.1 as u32);
+
+    // Tile is cached like any other image, so it's restored after reconnecting along with the rest
+    match show_image(id, device, opendeck_to_device(position), format, tile).await {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("Unable to write status tile of {}: {}", id, err);
+            false
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use mirajazz::types::HidDeviceInfo;
+
+#[cfg(target_os = "linux")]
+use async_hid::DeviceId;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+/// Resolves sysfs directory of the USB device the hidraw node belongs to
+#[cfg(target_os = "linux")]
+pub fn find_usb_device(dev: &HidDeviceInfo) -> Option<PathBuf> {
+    #[allow(irrefutable_let_patterns)]
+    let DeviceId::DevPath(path) = &dev.id else {
+        return None;
+    };
+
+    // /dev/hidrawN -> /sys/class/hidraw/hidrawN/device, which is a HID device,
+    // USB device is one of its parents and is the one that has `idVendor` attribute
+    let hid = std::fs::canonicalize(
+        PathBuf::from("/sys/class/hidraw")
+            .join(path.file_name()?)
+            .join("device"),
+    )
+    .ok()?;
+
+    hid.ancestors()
+        .find(|dir| dir.join("idVendor").exists())
+        .map(|dir| dir.to_path_buf())
+}
+
+/// Returns USB port the device is plugged into, like `1-2.3`
+#[cfg(target_os = "linux")]
+pub fn read_port(dev: &HidDeviceInfo) -> Option<String> {
+    Some(find_usb_device(dev)?.file_name()?.to_str()?.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_port(_dev: &HidDeviceInfo) -> Option<String> {
+    // Not implemented for other platforms yet
+    None
+}
@@ -0,0 +1,76 @@
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Logs how long stages of every traced image event took
+pub static TIMINGS: AtomicBool = AtomicBool::new(false);
+
+static NEXT_TRACE: AtomicU32 = AtomicU32::new(1);
+
+/// Image event being handled, with the stages it went through and when
+struct Trace {
+    id: u32,
+    started: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+tokio::task_local! {
+    static TRACE: RefCell<Trace>;
+}
+
+/// Handles image event with a new trace id, so its log lines can be told apart from the ones of other events
+pub async fn traced<F: Future>(future: F) -> F::Output {
+    let trace = Trace {
+        id: NEXT_TRACE.fetch_add(1, Ordering::Relaxed),
+        started: Instant::now(),
+        stages: Vec::new(),
+    };
+
+    TRACE
+        .scope(RefCell::new(trace), async move {
+            let output = future.await;
+
+            if TIMINGS.load(Ordering::Relaxed) {
+                TRACE.with(|trace| log_timings(&trace.borrow()));
+            }
+
+            output
+        })
+        .await
+}
+
+/// Returns id of the image event being handled, or 0 outside of one
+pub fn id() -> u32 {
+    TRACE.try_with(|trace| trace.borrow().id).unwrap_or(0)
+}
+
+/// Remembers that image event got through the stage, for the timing summary
+pub fn stage(name: &'static str) {
+    TRACE
+        .try_with(|trace| {
+            let mut trace = trace.borrow_mut();
+            let elapsed = trace.started.elapsed();
+
+            trace.stages.push((name, elapsed));
+        })
+        .ok();
+}
+
+fn log_timings(trace: &Trace) {
+    let mut stages = String::new();
+
+    for (name, elapsed) in trace.stages.iter() {
+        write!(stages, ", {} at {:?}", name, elapsed).unwrap(); // Writing to a string never fails
+    }
+
+    log::info!(
+        "Trace {} took {:?}{}",
+        trace.id,
+        trace.started.elapsed(),
+        stages
+    );
+}
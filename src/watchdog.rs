@@ -0,0 +1,76 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Device is quarantined after connecting this many times within the window
+const RECONNECT_LIMIT: usize = 10;
+const RECONNECT_WINDOW: Duration = Duration::from_secs(120);
+
+/// How long quarantined device waits before every connection attempt
+const QUARANTINE_DELAY: Duration = Duration::from_secs(60);
+
+/// Device that stays connected this long is considered healthy again
+pub const STABLE_TIME: Duration = Duration::from_secs(300);
+
+/// Recent connection times by device id
+static CONNECTS: LazyLock<Mutex<HashMap<String, VecDeque<Instant>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Ids of the devices that reconnect too often
+static QUARANTINED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Records connection attempt of the device, returning how long to wait before making it
+pub async fn connecting(id: &str) -> Duration {
+    let mut connects = CONNECTS.lock().await;
+    let times = connects.entry(id.to_string()).or_default();
+
+    let now = Instant::now();
+    times.push_back(now);
+
+    while times
+        .front()
+        .is_some_and(|time| now.duration_since(*time) > RECONNECT_WINDOW)
+    {
+        times.pop_front();
+    }
+
+    let mut quarantined = QUARANTINED.lock().await;
+
+    // No other attempts within the window means device was connected for a while
+    if times.len() == 1 && quarantined.remove(id) {
+        log::info!("Device {} is stable again, lifting quarantine", id);
+    }
+
+    if times.len() >= RECONNECT_LIMIT && quarantined.insert(id.to_string()) {
+        log::warn!(
+            "Device {} reconnected {} times in {:?}, check its cable and USB hub. Waiting {:?} between attempts from now on",
+            id,
+            times.len(),
+            RECONNECT_WINDOW,
+            QUARANTINE_DELAY
+        );
+    }
+
+    if quarantined.contains(id) {
+        QUARANTINE_DELAY
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// Lifts quarantine of the device after it stayed connected long enough
+pub async fn stable(id: &str) {
+    CONNECTS.lock().await.remove(id);
+
+    if QUARANTINED.lock().await.remove(id) {
+        log::info!("Device {} is stable again, lifting quarantine", id);
+    }
+}
+
+/// Returns ids of the quarantined devices
+pub async fn quarantined() -> Vec<String> {
+    QUARANTINED.lock().await.iter().cloned().collect()
+}
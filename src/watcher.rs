@@ -1,33 +1,139 @@
+use async_hid::DeviceId;
 use futures_lite::StreamExt;
 use mirajazz::{
     device::{DeviceWatcher, list_devices},
     error::MirajazzError,
     types::{DeviceLifecycleEvent, HidDeviceInfo},
 };
-use openaction::OUTBOUND_EVENT_MANAGER;
-use tokio_util::sync::CancellationToken;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    DEVICES, TOKENS, TRACKER,
+    DEVICES, RESET_TOKENS, SETTINGS, TOKENS, TRACKER, aliases,
     device::device_task,
-    mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES},
+    identity,
+    mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES, V1_SERIAL},
+    registration,
+    sysfs::read_port,
 };
 
+/// Wakes watcher up to look for connected devices that are not used yet
+static RESCAN: Notify = Notify::const_new();
+
+/// How long device has to stay unplugged before it's disconnected, because hubs that lose power
+/// for a moment re-enumerate all the devices behind them
+const QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// What watcher does about the device once its events are debounced
+#[derive(Debug, PartialEq)]
+enum Action {
+    Connect(HidDeviceInfo),
+    /// Device was unplugged and came back within the quiet period, so only its handle is replaced
+    Reconnect(HidDeviceInfo),
+    Disconnect(HidDeviceInfo),
+}
+
+/// Holds removals back for the quiet period, by device path, dropping the ones whose device is back by then
+#[derive(Default)]
+struct Debouncer {
+    removals: HashMap<DeviceId, (HidDeviceInfo, Instant)>,
+}
+
+impl Debouncer {
+    /// Returns what to do about the event right away, removals are returned by [Debouncer::expired] later
+    fn event(&mut self, ev: DeviceLifecycleEvent, now: Instant) -> Option<Action> {
+        match ev {
+            DeviceLifecycleEvent::Connected(info) => match self.removals.remove(&info.id) {
+                Some(_) => Some(Action::Reconnect(info)),
+                None => Some(Action::Connect(info)),
+            },
+            DeviceLifecycleEvent::Disconnected(info) => {
+                self.removals
+                    .insert(info.id.clone(), (info, now + QUIET_PERIOD));
+
+                None
+            }
+        }
+    }
+
+    /// Returns removals that are done waiting
+    fn expired(&mut self, now: Instant) -> Vec<Action> {
+        let expired: Vec<DeviceId> = self
+            .removals
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.removals.remove(&id))
+            .map(|(info, _)| Action::Disconnect(info))
+            .collect()
+    }
+
+    /// Returns when the next removal is done waiting
+    fn next_deadline(&self) -> Option<Instant> {
+        self.removals.values().map(|(_, deadline)| *deadline).min()
+    }
+}
+
+/// Raw serial numbers that were already reported as sanitized
+static SANITIZED_WARNED: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Builds device id that OpenDeck uses to keep profiles assigned to the device
+///
+/// Id must not change between replugs and restarts, so it only depends on VID, PID and serial number,
+/// and never on things like device path or order of enumeration
+///
+/// "v2"/"v3" devices without serial number can't have a stable id, so they are skipped.
+/// Devices with a serial that is nothing but garbage use USB port instead, which is only stable
+/// as long as device stays in the same port
 fn get_device_id(dev: &HidDeviceInfo) -> Option<String> {
     let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
 
     match kind.protocol_version() {
-        2 | 3 => Some(format!(
-            "{}-{}",
-            DEVICE_NAMESPACE,
-            dev.serial_number.clone()?,
-        )),
+        2 | 3 => {
+            let Some(raw) = dev.serial_number.as_ref() else {
+                log::warn!(
+                    "Device {:04x}:{:04x} has no serial number, unable to build a stable id for it",
+                    dev.vendor_id,
+                    dev.product_id
+                );
+
+                return None;
+            };
+
+            let serial = sanitize_serial(raw);
+
+            // Ids are built on every replug and rescan, so every serial is reported only once
+            if serial != *raw && SANITIZED_WARNED.lock().unwrap().insert(raw.clone()) {
+                log::warn!("Sanitized serial number {:?} into {:?}", raw, serial);
+            }
+
+            if serial.is_empty() {
+                let port = read_port(dev)?;
+
+                log::warn!("Serial number is empty, using USB port {} instead", port);
+
+                return Some(format!("{}-port-{}", DEVICE_NAMESPACE, port));
+            }
+
+            Some(format!("{}-{}", DEVICE_NAMESPACE, serial))
+        }
         1 => {
             // All the "v1" devices share the same serial. Hardcode it because Windows returns invalid serial for them
             // Also suffix v1 devices with the
             Some(format!(
-                "{}-355499441494-{}",
+                "{}-{}-{}",
                 DEVICE_NAMESPACE,
+                V1_SERIAL,
                 kind.id_suffix()
             ))
         }
@@ -35,21 +141,52 @@ fn get_device_id(dev: &HidDeviceInfo) -> Option<String> {
     }
 }
 
-fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
-    let id = get_device_id(&dev)?;
+/// Some clones report serials with NULs, whitespace or other garbage, which breaks profiles in OpenDeck,
+/// so drop control characters and replacement characters and trim whitespace around what's left.
+/// Everything else, like `-`, `_` or `.`, is a part of the serial and is kept as is
+fn sanitize_serial(serial: &str) -> String {
+    serial
+        .chars()
+        .filter(|c| !c.is_control() && *c != char::REPLACEMENT_CHARACTER)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+async fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
+    let id = aliases::resolve(get_device_id(&dev)?).await;
     let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
 
+    log::info!(
+        "Device {:04x}:{:04x} named {:?} is recognized as {} ({:?})",
+        dev.vendor_id,
+        dev.product_id,
+        dev.name,
+        kind.human_name(),
+        kind
+    );
+
+    // Names in unknown formats are left out silently, they are still logged above
+    if let Some(identity) = identity::parse(&dev.name) {
+        log::info!(
+            "Device {:04x}:{:04x} identifies itself as {:?}",
+            dev.vendor_id,
+            dev.product_id,
+            identity
+        );
+    }
+
     Some(CandidateDevice { id, dev, kind })
 }
 
 /// Returns devices that matches known pid/vid pairs
-async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
+pub async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     log::info!("Looking for candidate devices");
 
     let mut candidates: Vec<CandidateDevice> = Vec::new();
 
     for dev in list_devices(&QUERIES).await? {
-        if let Some(candidate) = device_info_to_candidate(dev.clone()) {
+        if let Some(candidate) = device_info_to_candidate(dev.clone()).await {
             candidates.push(candidate);
         } else {
             continue;
@@ -59,83 +196,463 @@ async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     Ok(candidates)
 }
 
+/// Logs how many devices are connected, so it's clear plugin is still waiting for devices if there are none
+fn log_status(ids: &HashMap<HidDeviceInfo, String>) {
+    if ids.is_empty() {
+        log::info!("No devices connected, waiting for them to be plugged in");
+    } else {
+        log::info!("{} devices connected", ids.len());
+    }
+}
+
+/// Asks watcher to look for connected devices that are not used yet, like the ones that are no longer excluded
+pub fn rescan() {
+    RESCAN.notify_one();
+}
+
+/// Spawns task for the device, unless plugin is shutting down and tasks are no longer awaited,
+/// or device is excluded in settings
+async fn spawn_device_task(tracker: &TaskTracker, candidate: CandidateDevice) {
+    if SETTINGS
+        .read()
+        .await
+        .excluded_devices
+        .contains(&candidate.id)
+    {
+        log::info!(
+            "Device {} is excluded in settings, skipping it",
+            candidate.id
+        );
+
+        return;
+    }
+
+    if tracker.is_closed() {
+        log::warn!(
+            "Not starting device {}, plugin is shutting down",
+            candidate.id
+        );
+
+        return;
+    }
+
+    let token = CancellationToken::new();
+
+    TOKENS
+        .write()
+        .await
+        .insert(candidate.id.clone(), token.clone());
+
+    tracker.spawn(device_task(candidate, token));
+
+    log::debug!("Spawned");
+}
+
 pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError> {
     let tracker = TRACKER.lock().await.clone();
 
     // Scans for connected devices that (possibly) we can use
-    let candidates = get_candidates().await?;
+    // Failing to list them is not fatal, they are still picked up by the watcher once plugged in again
+    let candidates = get_candidates().await.unwrap_or_else(|err| {
+        log::error!("Unable to list connected devices: {}", err);
+        vec![]
+    });
+
+    if candidates.is_empty() {
+        log::info!(
+            "No supported devices found, waiting for them to be plugged in. Looking for {:x?}",
+            QUERIES
+        );
+    }
 
     log::info!("Looking for connected devices");
 
+    // Remember ids of the spawned devices, because some of them can't be built again after device is gone
+    let mut ids: HashMap<HidDeviceInfo, String> = HashMap::new();
+
     for candidate in candidates {
         log::info!("New candidate {:#?}", candidate);
 
-        let token = CancellationToken::new();
-
-        TOKENS
-            .write()
-            .await
-            .insert(candidate.id.clone(), token.clone());
+        ids.insert(candidate.dev.clone(), candidate.id.clone());
 
-        tracker.spawn(device_task(candidate, token));
+        spawn_device_task(&tracker, candidate).await;
     }
 
     let mut watcher = DeviceWatcher::new();
     let mut watcher_stream = watcher.watch(&QUERIES).await?;
+    let mut debouncer = Debouncer::default();
 
     log::info!("Watcher is ready");
 
     loop {
+        let deadline = debouncer.next_deadline();
+
         let ev = tokio::select! {
             v = watcher_stream.next() => v,
+            _ = RESCAN.notified() => {
+                log::info!("Looking for devices that are not used yet");
+
+                for candidate in get_candidates().await.unwrap_or_default() {
+                    if DEVICES.read().await.contains_key(&candidate.id)
+                        || TOKENS.read().await.contains_key(&candidate.id)
+                    {
+                        continue;
+                    }
+
+                    ids.insert(candidate.dev.clone(), candidate.id.clone());
+                    spawn_device_task(&tracker, candidate).await;
+                }
+
+                log_status(&ids);
+
+                continue;
+            }
+            _ = sleep_until(deadline) => {
+                for action in debouncer.expired(Instant::now()) {
+                    act(&tracker, &mut ids, action).await;
+                }
+
+                continue;
+            }
             _ = token.cancelled() => None
         };
 
-        if let Some(ev) = ev {
-            log::info!("New device event: {:?}", ev);
+        let Some(ev) = ev else {
+            log::info!("Watcher is shutting down");
 
-            match ev {
-                DeviceLifecycleEvent::Connected(info) => {
-                    if let Some(candidate) = device_info_to_candidate(info) {
-                        // Don't add existing device again
-                        if DEVICES.read().await.contains_key(&candidate.id) {
-                            continue;
-                        }
+            break Ok(());
+        };
 
-                        let token = CancellationToken::new();
+        log::info!("New device event: {:?}", ev);
 
-                        TOKENS
-                            .write()
-                            .await
-                            .insert(candidate.id.clone(), token.clone());
+        if let Some(action) = debouncer.event(ev, Instant::now()) {
+            act(&tracker, &mut ids, action).await;
+        }
+    }
+}
 
-                        log::debug!("Spawning task for new device: {:?}", candidate);
-                        tracker.spawn(device_task(candidate, token));
-                        log::debug!("Spawned");
-                    }
+/// Sleeps until the deadline, or forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn act(tracker: &TaskTracker, ids: &mut HashMap<HidDeviceInfo, String>, action: Action) {
+    match action {
+        Action::Connect(info) => connected(tracker, ids, info).await,
+        Action::Reconnect(info) => {
+            let reset = match ids.get(&info) {
+                Some(id) => RESET_TOKENS.read().await.get(id).cloned(),
+                None => None,
+            };
+
+            // Old handle is dead after re-enumeration, so device task reconnects without deregistering it.
+            // Device that is already gone, or is still being initialized, is connected the usual way
+            match reset {
+                Some(reset) => {
+                    log::info!(
+                        "Device {:?} is back within {:?}, reconnecting to it",
+                        info.id,
+                        QUIET_PERIOD
+                    );
+                    reset.cancel();
                 }
-                DeviceLifecycleEvent::Disconnected(info) => {
-                    let id = get_device_id(&info)
-                        .expect("Unable to get device id, check mappings in Kind::from_vid_pid");
+                None => connected(tracker, ids, info).await,
+            }
+        }
+        Action::Disconnect(info) => disconnected(ids, info).await,
+    }
+}
 
-                    if let Some(token) = TOKENS.write().await.remove(&id) {
-                        log::info!("Sending cancel request for {}", id);
-                        token.cancel();
-                    }
+async fn connected(
+    tracker: &TaskTracker,
+    ids: &mut HashMap<HidDeviceInfo, String>,
+    info: HidDeviceInfo,
+) {
+    let Some(candidate) = device_info_to_candidate(info).await else {
+        return;
+    };
 
-                    DEVICES.write().await.remove(&id);
+    // Don't add existing device again, including the one that is still being initialized
+    if DEVICES.read().await.contains_key(&candidate.id)
+        || TOKENS.read().await.contains_key(&candidate.id)
+    {
+        return;
+    }
 
-                    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                        outbound.deregister_device(id.clone()).await.ok();
-                    }
+    ids.insert(candidate.dev.clone(), candidate.id.clone());
 
-                    log::info!("Disconnected device {}", id);
-                }
-            }
-        } else {
-            log::info!("Watcher is shutting down");
+    log::debug!("Spawning task for new device: {:?}", candidate);
+    spawn_device_task(tracker, candidate).await;
+    log_status(ids);
+}
 
-            break Ok(());
+async fn disconnected(ids: &mut HashMap<HidDeviceInfo, String>, info: HidDeviceInfo) {
+    // Devices without an id were never connected in the first place
+    let Some(id) = ids.remove(&info).or_else(|| get_device_id(&info)) else {
+        return;
+    };
+
+    if let Some(token) = TOKENS.write().await.remove(&id) {
+        log::info!("Sending cancel request for {}", id);
+        token.cancel();
+    }
+
+    DEVICES.write().await.remove(&id);
+
+    registration::unplugged(&id).await;
+
+    log::info!("Disconnected device {}", id);
+    log_status(ids);
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::mappings::*;
+    use async_hid::DeviceId;
+
+    /// VID and PID of every kind
+    const IDS: [(u16, u16, Kind); 15] = [
+        (MIRABOX_VID, HSV293S_PID, Kind::HSV293S),
+        (MIRABOX_VID, HSV293S_CREATOR_PID, Kind::HSV293SCREATOR),
+        (MIRABOX_2_VID, HSV293SV3_PID, Kind::HSV293SV3),
+        (MIRABOX_2_VID, HSV293SV3_1005_PID, Kind::HSV293SV3_1005),
+        (MIRABOX_VID, AKP153_PID, Kind::AKP153),
+        (AJAZZ_VID, AKP153E_PID, Kind::AKP153E),
+        (AJAZZ_VID, AKP153R_PID, Kind::AKP153R),
+        (AJAZZ_VID, AKP153E_REV2_PID, Kind::AKP153EREV2),
+        (AJAZZ_VID, AKP153R_REV2_PID, Kind::AKP153RREV2),
+        (MG_VID, MSD_ONE_PID, Kind::MSDONE),
+        (MADDOG_VID, GK150K_PID, Kind::GK150K),
+        (RISEMODE_VID, RMV01_PID, Kind::RMV01),
+        (SF_STC_VID, SF_STC_PID, Kind::SFSTC),
+        (TMICE_VID, TMICESC_PID, Kind::TMICESC),
+        (WOMIER_VID, D15_PID, Kind::D15),
+    ];
+
+    /// Builds device info like the one enumeration returns for the hidraw node
+    fn info(vid: u16, pid: u16, serial: Option<&str>, node: &str) -> HidDeviceInfo {
+        HidDeviceInfo {
+            id: DeviceId::DevPath(format!("/dev/{}", node).into()),
+            name: "355 35549".to_string(),
+            product_id: pid,
+            vendor_id: vid,
+            usage_id: 1,
+            usage_page: 65440,
+            serial_number: serial.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn id_is_the_same_after_replug() {
+        for (vid, pid, kind) in IDS {
+            let before = info(vid, pid, Some("A1B2C3D4E5F6"), "hidraw3");
+
+            // Device comes back on another node, with another name, like after replug into another port
+            let mut after = info(vid, pid, Some("A1B2C3D4E5F6"), "hidraw7");
+            after.name = "HOTSPOTEKUSB HID DEMO".to_string();
+
+            let id = get_device_id(&before);
+
+            assert!(id.is_some(), "{:?}", kind);
+            assert_eq!(get_device_id(&after), id, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn v1_id_does_not_depend_on_serial() {
+        for (vid, pid, kind) in IDS
+            .into_iter()
+            .filter(|(_, _, kind)| kind.protocol_version() == 1)
+        {
+            // Windows reports garbage serials for them
+            let id = get_device_id(&info(vid, pid, Some("355499441494"), "hidraw3"));
+
+            assert_eq!(get_device_id(&info(vid, pid, Some("???"), "hidraw7")), id);
+            assert_eq!(
+                get_device_id(&info(vid, pid, None, "hidraw7")),
+                id,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn device_without_stable_id_is_skipped() {
+        for (vid, pid, kind) in IDS
+            .into_iter()
+            .filter(|(_, _, kind)| kind.protocol_version() > 1)
+        {
+            // Port can't be read for a node that doesn't exist, and node alone would change on replug
+            assert_eq!(
+                get_device_id(&info(vid, pid, None, "hidraw999")),
+                None,
+                "{:?}",
+                kind
+            );
+            assert_eq!(
+                get_device_id(&info(vid, pid, Some("\0 \0"), "hidraw999")),
+                None,
+                "{:?}",
+                kind
+            );
         }
     }
+
+    #[test]
+    fn serial_with_embedded_nul_is_sanitized() {
+        assert_eq!(sanitize_serial("A1B2\0C3D4"), "A1B2C3D4");
+        assert_eq!(sanitize_serial("A1B2C3D4\0\0\0\0"), "A1B2C3D4");
+        assert_eq!(sanitize_serial("  A1B2C3D4 \r\n"), "A1B2C3D4");
+        assert_eq!(sanitize_serial("A1B2\u{FFFD}C3D4"), "A1B2C3D4");
+        assert_eq!(sanitize_serial("\0\0\0"), "");
+        assert_eq!(sanitize_serial(" \0 \0 "), "");
+    }
+
+    #[test]
+    fn clean_serial_is_kept_as_is() {
+        for serial in ["A1B2C3D4", "355-499_441.494", "SN 0042"] {
+            assert_eq!(sanitize_serial(serial), serial);
+        }
+    }
+
+    #[test]
+    fn overlong_serial_is_kept_whole() {
+        // Cutting it would give the same id to devices that differ only at the end
+        let serial = "0123456789ABCDEF".repeat(4);
+        assert_eq!(serial.len(), 64);
+
+        assert_eq!(sanitize_serial(&serial), serial);
+        assert_eq!(sanitize_serial(&format!("{}\0 \t", serial)), serial);
+    }
+
+    #[test]
+    fn sanitized_serial_gives_the_same_id_as_clean_one() {
+        let clean = info(MIRABOX_2_VID, HSV293SV3_PID, Some("A1B2C3D4"), "hidraw3");
+        let dirty = info(
+            MIRABOX_2_VID,
+            HSV293SV3_PID,
+            Some(" A1B2\0C3D4\0"),
+            "hidraw3",
+        );
+
+        assert_eq!(get_device_id(&dirty), get_device_id(&clean));
+        assert!(
+            get_device_id(&dirty)
+                .unwrap()
+                .chars()
+                .all(|c| !c.is_control() && !c.is_whitespace())
+        );
+    }
+
+    #[test]
+    fn devices_sharing_pid_are_told_apart_by_vid() {
+        // All of them are plugged in together, so none of them can be taken for another
+        let devices = [
+            (info(MG_VID, 0x1000, Some("A1"), "hidraw1"), Kind::MSDONE),
+            (
+                info(MADDOG_VID, 0x1000, Some("A2"), "hidraw2"),
+                Kind::GK150K,
+            ),
+            (info(WOMIER_VID, 0x1000, Some("A3"), "hidraw3"), Kind::D15),
+        ];
+
+        for (dev, kind) in &devices {
+            assert_eq!(
+                Kind::from_vid_pid(dev.vendor_id, dev.product_id).as_ref(),
+                Some(kind),
+                "{:04x}:{:04x}",
+                dev.vendor_id,
+                dev.product_id
+            );
+        }
+
+        let ids: HashSet<_> = devices
+            .iter()
+            .map(|(dev, _)| get_device_id(dev).unwrap())
+            .collect();
+        assert_eq!(ids.len(), devices.len());
+
+        // Other vendors using the same PID are not picked up as any of them
+        assert_eq!(Kind::from_vid_pid(0x1234, 0x1000), None);
+    }
+
+    #[test]
+    fn replug_within_quiet_period_only_reconnects() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        let devices = [
+            info(MIRABOX_2_VID, HSV293SV3_PID, Some("A1"), "hidraw1"),
+            info(AJAZZ_VID, AKP153E_REV2_PID, Some("A2"), "hidraw2"),
+        ];
+
+        // Hub drops and re-adds everything behind it
+        let mut actions = Vec::new();
+
+        for (offset, dev) in devices.iter().enumerate() {
+            let now = start + Duration::from_millis(offset as u64 * 10);
+            let ev = DeviceLifecycleEvent::Disconnected(dev.clone());
+
+            actions.extend(debouncer.event(ev, now));
+        }
+
+        for (offset, dev) in devices.iter().enumerate() {
+            let now = start + Duration::from_millis(200 + offset as u64 * 10);
+            let ev = DeviceLifecycleEvent::Connected(dev.clone());
+
+            actions.extend(debouncer.event(ev, now));
+        }
+
+        actions.extend(debouncer.expired(start + QUIET_PERIOD * 2));
+
+        assert_eq!(actions, devices.map(Action::Reconnect), "{:?}", actions);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn device_gone_for_quiet_period_is_disconnected() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        let dev = info(MIRABOX_2_VID, HSV293SV3_PID, Some("A1"), "hidraw1");
+
+        let ev = DeviceLifecycleEvent::Disconnected(dev.clone());
+        assert_eq!(debouncer.event(ev, start), None);
+        assert_eq!(debouncer.next_deadline(), Some(start + QUIET_PERIOD));
+
+        assert!(debouncer.expired(start + QUIET_PERIOD / 2).is_empty());
+        assert_eq!(
+            debouncer.expired(start + QUIET_PERIOD),
+            [Action::Disconnect(dev.clone())]
+        );
+
+        // Coming back later is a new device
+        let ev = DeviceLifecycleEvent::Connected(dev.clone());
+        assert_eq!(
+            debouncer.event(ev, start + QUIET_PERIOD * 2),
+            Some(Action::Connect(dev))
+        );
+    }
+
+    #[test]
+    fn other_device_coming_back_does_not_cancel_removal() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        let gone = info(MIRABOX_2_VID, HSV293SV3_PID, Some("A1"), "hidraw1");
+        let other = info(MIRABOX_2_VID, HSV293SV3_PID, Some("A2"), "hidraw2");
+
+        debouncer.event(DeviceLifecycleEvent::Disconnected(gone.clone()), start);
+
+        assert_eq!(
+            debouncer.event(DeviceLifecycleEvent::Connected(other.clone()), start),
+            Some(Action::Connect(other))
+        );
+        assert_eq!(
+            debouncer.expired(start + QUIET_PERIOD),
+            [Action::Disconnect(gone)]
+        );
+    }
 }